@@ -0,0 +1,21 @@
+/// Name and image of each built-in ROM, all classic public-domain programs
+/// from the `roms` directory.
+pub const BUILTINS: [(&str, &[u8]); 6] = [
+    ("maze", include_bytes!("../roms/MAZE")),
+    ("kaleid", include_bytes!("../roms/KALEID")),
+    ("pong", include_bytes!("../roms/PONG")),
+    ("brix", include_bytes!("../roms/BRIX")),
+    ("tetris", include_bytes!("../roms/TETRIS")),
+    ("ufo", include_bytes!("../roms/UFO")),
+];
+
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    BUILTINS
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|&(_, rom)| rom)
+}
+
+pub fn names() -> Vec<&'static str> {
+    BUILTINS.iter().map(|&(name, _)| name).collect()
+}
@@ -0,0 +1,399 @@
+use crate::{Address, Emulator, Instruction, Register, HEIGHT, WIDTH};
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+/// What the run loop should do once a command has been handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Prompt,
+    Step,
+    Continue,
+    Quit,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    paused: bool,
+    breakpoints: BTreeSet<u16>,
+    // One-shot breakpoint used by step-over.
+    temporary: Option<u16>,
+    // Address execution resumed from, so its own breakpoint doesn't fire
+    // again straight away.
+    resumed_at: Option<u16>,
+    // Opcode families that stop execution wherever they appear.
+    op_breaks: BTreeSet<&'static str>,
+}
+
+// Every opcode family by its pattern, with the mnemonic it disassembles to.
+pub const FAMILIES: [(&str, &str); 35] = [
+    ("0NNN", "SYS"),
+    ("00E0", "CLS"),
+    ("00EE", "RET"),
+    ("1NNN", "JP"),
+    ("2NNN", "CALL"),
+    ("3XNN", "SE"),
+    ("4XNN", "SNE"),
+    ("5XY0", "SE"),
+    ("6XNN", "LD"),
+    ("7XNN", "ADD"),
+    ("8XY0", "LD"),
+    ("8XY1", "OR"),
+    ("8XY2", "AND"),
+    ("8XY3", "XOR"),
+    ("8XY4", "ADD"),
+    ("8XY5", "SUB"),
+    ("8XY6", "SHR"),
+    ("8XY7", "SUBN"),
+    ("8XYE", "SHL"),
+    ("9XY0", "SNE"),
+    ("ANNN", "LD"),
+    ("BNNN", "JP"),
+    ("CXNN", "RND"),
+    ("DXYN", "DRW"),
+    ("EX9E", "SKP"),
+    ("EXA1", "SKNP"),
+    ("FX07", "LD"),
+    ("FX0A", "LD"),
+    ("FX15", "LD"),
+    ("FX18", "LD"),
+    ("FX1E", "ADD"),
+    ("FX29", "LD"),
+    ("FX33", "LD"),
+    ("FX55", "LD"),
+    ("FX65", "LD"),
+];
+
+// The family an instruction belongs to, e.g. DXYN.
+fn family(inst: &Instruction) -> &'static str {
+    match inst {
+        Instruction::SysCall(_) => "0NNN",
+        Instruction::ClearScreen => "00E0",
+        Instruction::Return => "00EE",
+        Instruction::Jump(_) => "1NNN",
+        Instruction::Call(_) => "2NNN",
+        Instruction::SkipIfEqual(..) => "3XNN",
+        Instruction::SkipIfNotEqual(..) => "4XNN",
+        Instruction::SkipIfRegistersEqual(..) => "5XY0",
+        Instruction::SetImmediate(..) => "6XNN",
+        Instruction::AddImmediate(..) => "7XNN",
+        Instruction::SetRegister(..) => "8XY0",
+        Instruction::OrRegister(..) => "8XY1",
+        Instruction::AndRegister(..) => "8XY2",
+        Instruction::XorRegister(..) => "8XY3",
+        Instruction::AdcRegister(..) => "8XY4",
+        Instruction::SwbRegister(..) => "8XY5",
+        Instruction::ShrRegister(..) => "8XY6",
+        Instruction::ReverseSwbRegister(..) => "8XY7",
+        Instruction::ShlRegister(..) => "8XYE",
+        Instruction::SkipIfRegistersNotEqual(..) => "9XY0",
+        Instruction::StoreAddress(_) => "ANNN",
+        Instruction::JumpOffset(_) => "BNNN",
+        Instruction::StoreRandom(..) => "CXNN",
+        Instruction::DrawSprite(..) => "DXYN",
+        Instruction::SkipIfPressed(_) => "EX9E",
+        Instruction::SkipIfNotPressed(_) => "EXA1",
+        Instruction::SetFromDelay(_) => "FX07",
+        Instruction::WaitKeyPress(_) => "FX0A",
+        Instruction::SetToDelay(_) => "FX15",
+        Instruction::SetToSound(_) => "FX18",
+        Instruction::AddAddress(_) => "FX1E",
+        Instruction::SetAddressToSprite(_) => "FX29",
+        Instruction::StoreBCD(_) => "FX33",
+        Instruction::StoreRegisters(_) => "FX55",
+        Instruction::LoadRegisters(_) => "FX65",
+    }
+}
+
+// Takes a family pattern like FX0A, or a mnemonic that only one family
+// disassembles to, like DRW.
+fn parse_family(s: &str) -> Result<&'static str> {
+    if let Some(&(pattern, _)) = FAMILIES.iter().find(|(p, _)| p.eq_ignore_ascii_case(s)) {
+        return Ok(pattern);
+    }
+    let matching: Vec<&str> = FAMILIES
+        .iter()
+        .filter(|(_, m)| m.eq_ignore_ascii_case(s))
+        .map(|&(p, _)| p)
+        .collect();
+    match matching.as_slice() {
+        [pattern] => Ok(pattern),
+        [] => bail!("'{}' is not an opcode like FX0A or a mnemonic like DRW", s),
+        patterns => bail!(
+            "{} covers {}; name one of them",
+            s.to_ascii_uppercase(),
+            patterns.join(" ")
+        ),
+    }
+}
+
+// Accepts 0x-prefixed hex or plain decimal.
+fn parse_number(s: &str) -> Result<u32> {
+    let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    n.map_err(|_| anyhow!("'{}' is not a number", s))
+}
+
+// Hex with or without 0x, as cheats are written and listed.
+fn parse_hex(s: &str, max: u32) -> Result<u32> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    match u32::from_str_radix(digits, 16) {
+        Ok(n) if n <= max => Ok(n),
+        Ok(_) => bail!("{} is larger than {:X}", s, max),
+        Err(_) => bail!("'{}' is not a hex number", s),
+    }
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    let n = parse_number(s)?;
+    if n > 0xff {
+        bail!("{} does not fit in a byte", s);
+    }
+    Ok(n as u8)
+}
+
+fn parse_address(s: &str) -> Result<u16> {
+    let n = parse_number(s)?;
+    if n > 0xfff {
+        bail!("{} is outside the 4K address space", s);
+    }
+    Ok(n as u16)
+}
+
+fn parse_register(s: &str) -> Result<Register> {
+    let index = s
+        .strip_prefix('V')
+        .or_else(|| s.strip_prefix('v'))
+        .and_then(|x| u8::from_str_radix(x, 16).ok())
+        .filter(|&x| x <= 0xf);
+    match index {
+        Some(x) => Ok(Register(x)),
+        None => bail!("'{}' is not a register, expected V0-VF", s),
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            paused: true,
+            ..Debugger::default()
+        }
+    }
+
+    /// Whether the run loop should stop and prompt before the next instruction.
+    pub fn should_break(&mut self, emu: &mut Emulator) -> bool {
+        let ip = emu.state.ip;
+        if self.temporary == Some(ip) {
+            self.temporary = None;
+            self.paused = true;
+        }
+        if !self.paused && self.resumed_at != Some(ip) {
+            if self.breakpoints.contains(&ip) {
+                self.paused = true;
+            } else if !self.op_breaks.is_empty() {
+                if let Ok(inst) = emu.memory.decode_at(ip) {
+                    self.paused = self.op_breaks.contains(family(&inst));
+                }
+            }
+        }
+        self.resumed_at = None;
+        self.paused
+    }
+
+    /// Reads commands from stdin until one resumes execution. Returns false
+    /// if the user asked to quit.
+    pub fn prompt(&mut self, emu: &mut Emulator) -> Result<bool> {
+        let stdin = std::io::stdin();
+        loop {
+            let ip = emu.state.ip;
+            match emu.memory.decode_at(ip) {
+                Ok(inst) if inst.is_control_flow() => {
+                    let target = match inst {
+                        Instruction::Return => emu.memory.stack.entries.last().map(|&a| Address(a)),
+                        _ => inst.jump_target(&emu.state),
+                    };
+                    match target {
+                        Some(a) => print!("{:03X}: {} (-> {:03X}) > ", ip, inst, a.0),
+                        None => print!("{:03X}: {} > ", ip, inst),
+                    }
+                }
+                Ok(inst) => print!("{:03X}: {} > ", ip, inst),
+                Err(_) => print!("{:03X}: ??? > ", ip),
+            }
+            std::io::stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(false);
+            }
+            match self.command(emu, &line) {
+                Ok(Action::Prompt) => {}
+                Ok(Action::Quit) => return Ok(false),
+                Ok(_) => return Ok(true),
+                Err(e) => println!("error: {}", e),
+            }
+        }
+    }
+
+    fn resume(&mut self, emu: &Emulator) -> Action {
+        self.paused = false;
+        self.resumed_at = Some(emu.state.ip);
+        Action::Continue
+    }
+
+    // Prints the instructions at and after addr.
+    fn list(&self, emu: &mut Emulator, addr: u16) {
+        for ip in (addr..=0xffe).step_by(2).take(8) {
+            let marker = if ip == emu.state.ip { '>' } else { ' ' };
+            match emu.memory.decode_at(ip) {
+                Ok(inst) => println!("{} {:03X}: {}", marker, ip, inst),
+                Err(_) => println!("{} {:03X}: ???", marker, ip),
+            }
+        }
+    }
+
+    pub fn command(&mut self, emu: &mut Emulator, line: &str) -> Result<Action> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => Ok(Action::Prompt),
+            ["s"] | ["step"] => {
+                self.paused = true;
+                Ok(Action::Step)
+            }
+            ["n"] | ["next"] => match emu.memory.decode_at(emu.state.ip) {
+                Ok(Instruction::Call(_)) => {
+                    self.temporary = Some(emu.state.ip + 2);
+                    Ok(self.resume(emu))
+                }
+                _ => {
+                    self.paused = true;
+                    Ok(Action::Step)
+                }
+            },
+            ["c"] | ["continue"] => Ok(self.resume(emu)),
+            ["q"] | ["quit"] => Ok(Action::Quit),
+            ["b", addr] | ["break", addr] => {
+                self.breakpoints.insert(parse_address(addr)?);
+                Ok(Action::Prompt)
+            }
+            ["d", addr] | ["delete", addr] => {
+                let addr = parse_address(addr)?;
+                if !self.breakpoints.remove(&addr) {
+                    bail!("no breakpoint at {:03X}", addr);
+                }
+                Ok(Action::Prompt)
+            }
+            ["break-op", op] => {
+                self.op_breaks.insert(parse_family(op)?);
+                Ok(Action::Prompt)
+            }
+            ["delete-op", op] => {
+                let op = parse_family(op)?;
+                if !self.op_breaks.remove(op) {
+                    bail!("no breakpoint on {}", op);
+                }
+                Ok(Action::Prompt)
+            }
+            ["l"] | ["list"] => {
+                self.list(emu, emu.state.ip);
+                Ok(Action::Prompt)
+            }
+            ["l", addr] | ["list", addr] => {
+                self.list(emu, parse_address(addr)?);
+                Ok(Action::Prompt)
+            }
+            ["cheat", spec] => {
+                let (addr, value) = match spec.find(':') {
+                    Some(i) => (&spec[..i], &spec[i + 1..]),
+                    None => bail!("expected ADDR:VALUE in hex, like 3a0:09"),
+                };
+                emu.add_cheat(parse_hex(addr, 0xfff)? as u16, parse_hex(value, 0xff)? as u8);
+                Ok(Action::Prompt)
+            }
+            ["cheats"] => {
+                for (addr, value) in &emu.cheats {
+                    println!("{:03X}: {:02X}", addr, value);
+                }
+                Ok(Action::Prompt)
+            }
+            ["uncheat", addr] => {
+                let addr = parse_hex(addr, 0xfff)? as u16;
+                if emu.cheats.remove(&addr).is_none() {
+                    bail!("no cheat at {:03X}", addr);
+                }
+                Ok(Action::Prompt)
+            }
+            ["r"] | ["regs"] => {
+                let s = &emu.state;
+                println!(
+                    "ip={:03X} i={:03X} sp={} dt={:02X} st={:02X}",
+                    s.ip,
+                    s.address.0,
+                    emu.memory.stack.len(),
+                    emu.delay_timer(),
+                    emu.sound_timer()
+                );
+                let v: Vec<String> = (0..16)
+                    .map(|i| format!("V{:X}={:02X}", i, s.v[i]))
+                    .collect();
+                println!("{}", v.join(" "));
+                println!(
+                    "cycle {} frame {}",
+                    emu.cycles_executed(),
+                    emu.emulated_frame()
+                );
+                if emu.last_add_overflowed() {
+                    println!("last ADD Vx, NN wrapped");
+                }
+                if let Some(cycles) = emu.cycles_remaining_in_frame() {
+                    println!("{} cycles left in frame", cycles);
+                }
+                Ok(Action::Prompt)
+            }
+            ["set", "reg", r, value] => {
+                emu.set_register(parse_register(r)?, parse_byte(value)?);
+                Ok(Action::Prompt)
+            }
+            ["set", "i", value] => {
+                emu.set_address(Address(parse_address(value)?));
+                Ok(Action::Prompt)
+            }
+            ["set", "dt", value] => {
+                emu.set_delay_timer(parse_byte(value)?);
+                Ok(Action::Prompt)
+            }
+            ["set", "st", value] => {
+                emu.set_sound_timer(parse_byte(value)?);
+                Ok(Action::Prompt)
+            }
+            ["set", "mem", addr, value] => {
+                emu.write_memory(parse_address(addr)?, parse_byte(value)?)?;
+                Ok(Action::Prompt)
+            }
+            ["set", "pixel", x, y, state] => {
+                let x = parse_number(x)? as usize;
+                let y = parse_number(y)? as usize;
+                if x >= WIDTH || y >= HEIGHT {
+                    bail!("pixel {},{} is off the {}x{} screen", x, y, WIDTH, HEIGHT);
+                }
+                let on = match *state {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!("expected on or off, got '{}'", state),
+                };
+                emu.set_pixel(x, y, on);
+                Ok(Action::Prompt)
+            }
+            _ => bail!(
+                "unknown command; try step, next, continue, break ADDR, delete ADDR, break-op OP, \
+                 delete-op OP, list [ADDR], cheat ADDR:NN, cheats, uncheat ADDR, regs, quit, \
+                 set reg Vx NN, set i NNN, set dt NN, set st NN, set mem ADDR NN, set pixel X Y on|off"
+            ),
+        }
+    }
+}
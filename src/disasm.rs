@@ -0,0 +1,149 @@
+use crate::{Address, Instruction, State};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+const START: usize = 0x200;
+
+// Where control can go from an instruction other than the next one, as far
+// as can be told without running it. BNNN depends on V0, so gets no label.
+// Nothing past the end of memory can run, so that has no targets either.
+fn target(ip: usize, inst: &Instruction) -> Option<Address> {
+    match inst {
+        Instruction::JumpOffset(_) => None,
+        _ if ip > 0xfff => None,
+        _ => inst.jump_target(&State {
+            ip: ip as u16,
+            ..State::default()
+        }),
+    }
+}
+
+fn label(a: Address) -> String {
+    format!("L_{:03X}", a.0)
+}
+
+/// Linear disassembly of a ROM loaded at 0x200. Jump, call and skip targets
+/// are collected in a first pass so the listing can mark them with labels.
+/// Targets at an odd address or outside the ROM have no line to label, so
+/// references to them give the bare address marked off-grid.
+pub fn disassemble(rom: &[u8]) -> String {
+    let decoded: Vec<(usize, u16, Option<Instruction>)> = rom
+        .chunks(2)
+        .enumerate()
+        .map(|(i, word)| {
+            let ip = START + 2 * i;
+            let hi = word[0];
+            let lo = word.get(1).copied().unwrap_or(0);
+            // ip only goes into the error, which is dropped.
+            let inst = Instruction::decode_bytes(ip as u16, hi, lo).ok();
+            (ip, ((hi as u16) << 8) | lo as u16, inst)
+        })
+        .collect();
+
+    let labels: BTreeSet<usize> = decoded
+        .iter()
+        .filter_map(|(ip, _, inst)| inst.as_ref().and_then(|i| target(*ip, i)))
+        .map(|a| a.0 as usize)
+        .collect();
+
+    let on_grid =
+        |a: Address| a.0 & 1 == 0 && (START..START + 2 * decoded.len()).contains(&(a.0 as usize));
+
+    let mut out = String::new();
+    for (ip, word, inst) in &decoded {
+        if labels.contains(ip) {
+            writeln!(out, "{}:", label(Address(*ip as u16))).unwrap();
+        }
+        match inst {
+            Some(inst) => {
+                let text = inst.to_string();
+                match target(*ip, inst) {
+                    Some(a) if on_grid(a) => writeln!(
+                        out,
+                        "    {:03X}: {:04X}  {:<20} ; {}",
+                        ip,
+                        word,
+                        text,
+                        label(a)
+                    ),
+                    Some(a) => writeln!(
+                        out,
+                        "    {:03X}: {:04X}  {:<20} ; {:03X} (off-grid)",
+                        ip, word, text, a.0
+                    ),
+                    None => writeln!(out, "    {:03X}: {:04X}  {}", ip, word, text),
+                }
+            }
+            None => writeln!(out, "    {:03X}: {:04X}  ???", ip, word),
+        }
+        .unwrap();
+    }
+    out
+}
+
+// Fraction of the ROM's 16-bit words that decode, optionally with the two
+// bytes of each word exchanged. Counting starts where an opening jump
+// lands, as many ROMs jump over a text banner, often to an odd address.
+// Most byte pairs decode as something, so only words that make sense in the
+// ROM count: no 0NNN, which next to no program uses, and no jumps or calls
+// outside it.
+pub fn decode_rate(rom: &[u8], swapped: bool) -> f64 {
+    let mut rom = rom.to_vec();
+    if swapped {
+        rom.chunks_exact_mut(2).for_each(|word| word.swap(0, 1));
+    }
+    let end = START + rom.len();
+    let inside = |a: Address| (START..end).contains(&(a.0 as usize));
+    // Errors are only counted, so the address they would name is not needed.
+    let decode = |offset: usize| Instruction::decode_bytes(0, rom[offset], rom[offset + 1]);
+    let entry = match rom.len() {
+        0 | 1 => return 0.0,
+        _ => match decode(0) {
+            Ok(Instruction::Jump(a)) if inside(a) && a.0 as usize - START + 1 < rom.len() => {
+                a.0 as usize - START
+            }
+            _ => 0,
+        },
+    };
+    let offsets: Vec<usize> = (entry..rom.len() - 1).step_by(2).collect();
+    let valid = offsets
+        .iter()
+        .filter(|&&offset| match decode(offset) {
+            Ok(Instruction::SysCall(_)) | Err(_) => false,
+            Ok(Instruction::Jump(a)) | Ok(Instruction::Call(a)) => inside(a),
+            Ok(_) => true,
+        })
+        .count();
+    valid as f64 / offsets.len() as f64
+}
+
+/// Whether the ROM looks byte-swapped: its words decode far more often with
+/// their bytes exchanged.
+pub fn looks_swapped(rom: &[u8]) -> bool {
+    decode_rate(rom, true) > decode_rate(rom, false) + 0.15
+}
+
+/// Report for --analyze on how cleanly the ROM decodes as-is and swapped.
+pub fn analyze(rom: &[u8]) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "decodes as-is: {:.0}%",
+        100.0 * decode_rate(rom, false)
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "decodes byte-swapped: {:.0}%",
+        100.0 * decode_rate(rom, true)
+    )
+    .unwrap();
+    if looks_swapped(rom) {
+        writeln!(
+            out,
+            "the words look byte-swapped; swap each pair of bytes before running it"
+        )
+        .unwrap();
+    }
+    out
+}
@@ -0,0 +1,280 @@
+use crate::options::Options;
+use crate::{fnv1a, Config, Emulator, Instruction, StepEvent, TimingMode};
+use anyhow::{bail, Context, Result};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// How many instructions a headless run executes between looks at the
+// wall clock for --max-seconds.
+const CLOCK_CHECK_INTERVAL: u64 = 1024;
+
+/// Exit status of a headless run that hit `--max-cycles` or `--max-seconds`.
+pub const TIMED_OUT_STATUS: i32 = 2;
+
+/// A piece of final state to print after a headless run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dump {
+    Registers,
+    Memory { start: u16, len: u16 },
+    Screen,
+    ScreenHash,
+    Fingerprint,
+}
+
+impl std::str::FromStr for Dump {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Dump> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["regs"] => Ok(Dump::Registers),
+            ["screen"] => Ok(Dump::Screen),
+            ["screen-hash"] => Ok(Dump::ScreenHash),
+            ["fingerprint"] => Ok(Dump::Fingerprint),
+            ["mem", start, len] => {
+                let start = u16::from_str_radix(start.trim_start_matches("0x"), 16)?;
+                let len: u16 = len.parse()?;
+                if start as usize + len as usize > 0x1000 {
+                    bail!("{} runs past the end of memory", s);
+                }
+                Ok(Dump::Memory { start, len })
+            }
+            _ => bail!(
+                "expected regs, mem:0xNNN:LEN, screen, screen-hash or fingerprint, got '{}'",
+                s
+            ),
+        }
+    }
+}
+
+/// Formats final state as `key=value` lines, or the ASCII display.
+pub fn dump(emulator: &Emulator, what: Dump) -> String {
+    let mut out = String::new();
+    match what {
+        Dump::Registers => {
+            let s = &emulator.state;
+            writeln!(out, "ip={:03x}", s.ip).unwrap();
+            writeln!(out, "i={:03x}", s.address.0).unwrap();
+            writeln!(out, "sp={}", emulator.memory.stack.len()).unwrap();
+            writeln!(out, "dt={:02x}", emulator.delay_timer()).unwrap();
+            writeln!(out, "st={:02x}", emulator.sound_timer()).unwrap();
+            for (i, v) in s.v.iter().enumerate() {
+                writeln!(out, "v{:x}={:02x}", i, v).unwrap();
+            }
+        }
+        Dump::Memory { start, len } => {
+            let bytes = &emulator.memory.mem[start as usize..(start + len) as usize];
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "mem:{:03x}={}", start, hex.join(" ")).unwrap();
+        }
+        Dump::Screen => out.push_str(&emulator.render_ascii()),
+        Dump::ScreenHash => writeln!(out, "screen_hash={:016x}", emulator.screen_hash()).unwrap(),
+        Dump::Fingerprint => writeln!(out, "fingerprint={:016x}", fingerprint(emulator)).unwrap(),
+    }
+    out
+}
+
+/// One hash of the machine state: registers, I, ip, the stack, a hash of
+/// RAM and the screen hash. Any change in how a ROM executes is likely to
+/// show up in it.
+pub fn fingerprint(emulator: &Emulator) -> u64 {
+    let s = &emulator.state;
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&s.v);
+    bytes.extend_from_slice(&s.address.0.to_le_bytes());
+    bytes.extend_from_slice(&s.ip.to_le_bytes());
+    bytes.extend_from_slice(&(emulator.memory.stack.len() as u16).to_le_bytes());
+    for entry in &emulator.memory.stack.entries {
+        bytes.extend_from_slice(&entry.to_le_bytes());
+    }
+    bytes.extend_from_slice(&fnv1a(&emulator.memory.mem).to_le_bytes());
+    bytes.extend_from_slice(&emulator.screen_hash().to_le_bytes());
+    fnv1a(&bytes)
+}
+
+// Headless runs always count time in instructions so they are reproducible,
+// and always use a fixed seed.
+fn headless_config(config: &Config) -> Config {
+    Config {
+        timing: match config.timing {
+            TimingMode::Legacy => TimingMode::Virtual {
+                cycles_per_tick: 10,
+            },
+            t => t,
+        },
+        seed: Some(config.seed.unwrap_or(0)),
+        ..config.clone()
+    }
+}
+
+fn load(path: &str, config: &Config) -> Result<Emulator> {
+    let mut emulator = Emulator::new(config);
+    let rom = std::fs::read(path).with_context(|| format!("reading {}", path))?;
+    emulator.load_rom(&rom)?;
+    Ok(emulator)
+}
+
+/// How a headless run ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// All frames ran, or the program halted or exited.
+    Finished,
+    /// `--max-cycles` or `--max-seconds` ran out first.
+    TimedOut,
+}
+
+/// Runs a ROM without a window for `--frames` frames, or until it halts,
+/// then prints the requested final state. A run that hits `--max-cycles` or
+/// `--max-seconds` first also reports where it stopped on stderr.
+pub fn run(options: &Options) -> Result<Stop> {
+    let config = headless_config(&options.config);
+    let (mut emulator, _) = crate::start(options, &config)?;
+    let max_time = options.max_seconds.map(Duration::from_secs_f64);
+    let started = Instant::now();
+    let mut frames = 0;
+    let mut last_ip = None;
+    let mut stop = Stop::Finished;
+    while frames < options.frames && !emulator.is_halted() && !emulator.state.finished {
+        let cycles = emulator.cycles_executed();
+        let out_of_cycles = options.max_cycles.into_iter().any(|max| cycles >= max);
+        let out_of_time = cycles % CLOCK_CHECK_INTERVAL == 0
+            && max_time.into_iter().any(|max| started.elapsed() >= max);
+        if out_of_cycles || out_of_time {
+            stop = Stop::TimedOut;
+            break;
+        }
+        last_ip = Some(emulator.state.ip);
+        if emulator.step()? == StepEvent::FrameComplete {
+            frames += 1;
+        }
+    }
+    for &what in &options.dumps {
+        print!("{}", dump(&emulator, what));
+    }
+    if stop == Stop::TimedOut {
+        eprintln!("{}", timeout_summary(&mut emulator, last_ip));
+    }
+    Ok(stop)
+}
+
+/// Where a timed-out run stopped: ip, the last instruction executed and the
+/// number executed.
+pub fn timeout_summary(emulator: &mut Emulator, last_ip: Option<u16>) -> String {
+    let last = match last_ip {
+        Some(ip) => match emulator.memory.decode_at(ip) {
+            Ok(inst) => format!("{:03x} {}", ip, inst),
+            Err(_) => format!("{:03x} (undecodable)", ip),
+        },
+        None => "none".to_string(),
+    };
+    format!(
+        "timed out: ip={:03x} last={} cycles={}",
+        emulator.state.ip,
+        last,
+        emulator.cycles_executed()
+    )
+}
+
+pub fn run_frame(emulator: &mut Emulator) -> Result<()> {
+    while emulator.step()? != StepEvent::FrameComplete {}
+    Ok(())
+}
+
+/// What a ROM spent its time on during a headless run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub frames: u64,
+    pub draws: u64,
+    pub input_polls: u64,
+}
+
+/// Runs up to `frames` frames, or until the program halts, counting DXYN
+/// and key checks as they execute.
+pub fn profile(emulator: &mut Emulator, frames: u64) -> Result<Profile> {
+    let mut profile = Profile::default();
+    while profile.frames < frames && !emulator.is_halted() && !emulator.state.finished {
+        match Instruction::decode(&emulator.state, &emulator.memory)? {
+            Instruction::DrawSprite(..) => profile.draws += 1,
+            Instruction::SkipIfPressed(_)
+            | Instruction::SkipIfNotPressed(_)
+            | Instruction::WaitKeyPress(_) => profile.input_polls += 1,
+            _ => {}
+        }
+        if emulator.step()? == StepEvent::FrameComplete {
+            profile.frames += 1;
+        }
+    }
+    Ok(profile)
+}
+
+/// Turns a profile into timing flags. ROMs that draw most frames are paced
+/// by their drawing and want display wait; ones that neither draw nor read
+/// keys are grinding through computation and want more instructions per
+/// frame. Anything else gets the default.
+pub fn suggest(profile: &Profile) -> &'static str {
+    let frames = profile.frames.max(1) as f64;
+    let draws = profile.draws as f64 / frames;
+    let polls = profile.input_polls as f64 / frames;
+    if draws >= 0.5 {
+        "--timing frame:15 --display-wait"
+    } else if draws < 0.05 && polls < 0.05 {
+        "--timing frame:30"
+    } else {
+        "--timing frame:10"
+    }
+}
+
+/// Profiles a ROM headless for `--frames` frames with no input and prints
+/// suggested timing flags.
+pub fn suggest_speed(options: &Options) -> Result<()> {
+    let config = headless_config(&options.config);
+    let (mut emulator, _) = crate::start(options, &config)?;
+    let profile = profile(&mut emulator, options.frames)?;
+    let frames = profile.frames.max(1) as f64;
+    println!("frames: {}", profile.frames);
+    println!("draws per frame: {:.2}", profile.draws as f64 / frames);
+    println!(
+        "key checks per frame: {:.2}",
+        profile.input_polls as f64 / frames
+    );
+    println!("suggested: {}", suggest(&profile));
+    Ok(())
+}
+
+/// Runs both emulators in lockstep and returns the first frame, counting
+/// from 1, whose display differs. Neither gets any key input: there is no
+/// way to script presses yet, so ROMs that wait for a key are only compared
+/// up to that point.
+pub fn first_divergence(a: &mut Emulator, b: &mut Emulator, frames: u64) -> Result<Option<u64>> {
+    for frame in 1..=frames {
+        run_frame(a).context("first ROM")?;
+        run_frame(b).context("second ROM")?;
+        if a.screen != b.screen {
+            return Ok(Some(frame));
+        }
+    }
+    Ok(None)
+}
+
+/// Compares two ROMs frame by frame with no input. Prints a side-by-side of
+/// the first differing frame and returns whether they matched throughout.
+pub fn diff_roms(path_a: &str, path_b: &str, options: &Options) -> Result<bool> {
+    let config = headless_config(&options.config);
+    let mut a = load(path_a, &config)?;
+    let mut b = load(path_b, &config)?;
+    match first_divergence(&mut a, &mut b, options.frames)? {
+        Some(frame) => {
+            println!("frame {} differs ({} | {}):", frame, path_a, path_b);
+            let (a, b) = (a.render_ascii(), b.render_ascii());
+            for (row_a, row_b) in a.lines().zip(b.lines()) {
+                let marker = if row_a == row_b { "" } else { " *" };
+                println!("{} | {}{}", row_a, row_b, marker);
+            }
+            Ok(false)
+        }
+        None => {
+            println!("no difference in {} frames", options.frames);
+            Ok(true)
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::KEYMAP;
+use anyhow::{anyhow, bail, Context, Result};
+use minifb::Key;
+
+// Keys that can be named in a keymap file, by their minifb names.
+const KEY_NAMES: [Key; 84] = [
+    Key::Key0,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+    Key::F10,
+    Key::F11,
+    Key::F12,
+    Key::Up,
+    Key::Down,
+    Key::Left,
+    Key::Right,
+    Key::Space,
+    Key::Tab,
+    Key::Enter,
+    Key::Backspace,
+    Key::Minus,
+    Key::Equal,
+    Key::Comma,
+    Key::Period,
+    Key::Slash,
+    Key::Semicolon,
+    Key::Apostrophe,
+    Key::LeftBracket,
+    Key::RightBracket,
+    Key::Backslash,
+    Key::Backquote,
+    Key::LeftShift,
+    Key::RightShift,
+    Key::LeftCtrl,
+    Key::RightCtrl,
+    Key::LeftAlt,
+    Key::RightAlt,
+    Key::NumPad0,
+    Key::NumPad1,
+    Key::NumPad2,
+    Key::NumPad3,
+    Key::NumPad4,
+    Key::NumPad5,
+    Key::NumPad6,
+    Key::NumPad7,
+    Key::NumPad8,
+    Key::NumPad9,
+    Key::Pause,
+];
+
+/// Looks up a key by name, ignoring case. A lone digit means that key on
+/// the number row.
+pub fn parse_key(name: &str) -> Result<Key> {
+    let name = match name {
+        d if d.len() == 1 && d.as_bytes()[0].is_ascii_digit() => format!("Key{}", d),
+        n => n.to_string(),
+    };
+    KEY_NAMES
+        .iter()
+        .copied()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(&name))
+        .ok_or_else(|| anyhow!("unknown key '{}'", name))
+}
+
+/// A physical key, optionally only while a modifier is held.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub key: Key,
+    pub modifier: Option<Key>,
+}
+
+impl Binding {
+    fn new(key: Key) -> Binding {
+        Binding {
+            key,
+            modifier: None,
+        }
+    }
+
+    /// True when the key was just pressed and the modifier, if any, is down.
+    pub fn fired(&self, is_pressed: impl Fn(Key) -> bool, is_down: impl Fn(Key) -> bool) -> bool {
+        is_pressed(self.key) && self.modifier.into_iter().all(is_down)
+    }
+}
+
+impl std::str::FromStr for Binding {
+    type Err = anyhow::Error;
+
+    // `KEY` or `MODIFIER+KEY`.
+    fn from_str(s: &str) -> Result<Binding> {
+        match s.rsplitn(2, '+').collect::<Vec<_>>().as_slice() {
+            [key] => Ok(Binding::new(parse_key(key.trim())?)),
+            [key, modifier] => Ok(Binding {
+                key: parse_key(key.trim())?,
+                modifier: Some(parse_key(modifier.trim())?),
+            }),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Keys for the emulator's own functions, kept apart from the hex pad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControlBindings {
+    pub save_state: Binding,
+    pub load_state: Binding,
+    pub reset: Binding,
+}
+
+impl Default for ControlBindings {
+    fn default() -> Self {
+        ControlBindings {
+            save_state: Binding::new(Key::F5),
+            load_state: Binding::new(Key::F9),
+            reset: Binding::new(Key::F2),
+        }
+    }
+}
+
+/// The host key for each CHIP-8 key 0-F, and the emulator controls.
+#[derive(Clone, Copy, Debug)]
+pub struct Keymap {
+    pub pad: [Key; 16],
+    pub controls: ControlBindings,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            pad: KEYMAP,
+            controls: ControlBindings::default(),
+        }
+    }
+}
+
+/// Parses a keymap file of `name = binding` lines. Names are a hex pad key
+/// `0`-`f`, `save-state`, `load-state` or `reset`; anything not mentioned
+/// keeps its default. Blank lines and lines starting with `#` are skipped.
+pub fn parse(text: &str) -> Result<Keymap> {
+    let mut keymap = Keymap::default();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => bail!("line {}: expected NAME = KEY", n + 1),
+        };
+        let binding: Binding = value.parse().with_context(|| format!("line {}", n + 1))?;
+        match name {
+            "save-state" => keymap.controls.save_state = binding,
+            "load-state" => keymap.controls.load_state = binding,
+            "reset" => keymap.controls.reset = binding,
+            pad if pad.len() == 1 => {
+                let k = u8::from_str_radix(pad, 16)
+                    .map_err(|_| anyhow!("line {}: unknown name '{}'", n + 1, pad))?;
+                if binding.modifier.is_some() {
+                    bail!("line {}: hex pad keys cannot take a modifier", n + 1);
+                }
+                keymap.pad[k as usize] = binding.key;
+            }
+            _ => bail!("line {}: unknown name '{}'", n + 1, name),
+        }
+    }
+    Ok(keymap)
+}
+
+pub fn load(path: &str) -> Result<Keymap> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+    parse(&text).with_context(|| format!("in {}", path))
+}
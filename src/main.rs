@@ -1,10 +1,26 @@
-use anyhow::{bail, Result};
-use minifb::{Key, Scale, Window, WindowOptions};
+use anyhow::{anyhow, bail, Context, Result};
+use debugger::Debugger;
+use keymap::Keymap;
+use minifb::{Key, KeyRepeat, MouseMode, Scale, Window, WindowOptions};
+use options::Options;
 use rand::prelude::*;
+use std::collections::{BTreeMap, HashSet};
 use std::time::{Duration, Instant};
 
+mod builtins;
+mod debugger;
+mod disasm;
+mod headless;
+mod keymap;
+mod options;
+mod snapshot;
+
 const WIDTH: usize = 64;
 const HEIGHT: usize = 32;
+const FONT_SIZE: usize = 5 * 0x10;
+const ON_COLOR: u32 = 0xffffffff;
+const OFF_COLOR: u32 = 0xff000000;
+const SOUND_COLOR: u32 = 0xffff4040;
 
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 struct Address(u16);
@@ -13,12 +29,15 @@ struct Register(u8);
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 struct Constant(u8);
 
+// Original COSMAC VIP interpreter behaviour versus the later CHIP-48 and
+// SUPER-CHIP one, for instructions where they disagree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Behavior {
     Old,
     New,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum Instruction {
     SysCall(Address),
     ClearScreen,
@@ -59,10 +78,15 @@ enum Instruction {
 
 impl Instruction {
     fn decode(state: &State, mem: &Memory) -> Result<Instruction> {
-        let a = (mem.mem[state.ip as usize + 0] & 0xf0) >> 4;
-        let b = (mem.mem[state.ip as usize + 0] & 0x0f) >> 0;
-        let c = (mem.mem[state.ip as usize + 1] & 0xf0) >> 4;
-        let d = (mem.mem[state.ip as usize + 1] & 0x0f) >> 0;
+        let [hi, lo] = mem.fetch(state.ip)?;
+        Instruction::decode_bytes(state.ip, hi, lo)
+    }
+
+    fn decode_bytes(ip: u16, hi: u8, lo: u8) -> Result<Instruction> {
+        let a = (hi & 0xf0) >> 4;
+        let b = (hi & 0x0f) >> 0;
+        let c = (lo & 0xf0) >> 4;
+        let d = (lo & 0x0f) >> 0;
 
         let addr = |x: u8, y: u8, z: u8| {
             Address(((x as u16) << 8) | ((y as u16) << 4) | ((z as u16) << 0))
@@ -106,45 +130,69 @@ impl Instruction {
             0xf if c == 0x5 && d == 0x5 => Instruction::StoreRegisters(Register(b)),
             0xf if c == 0x6 && d == 0x5 => Instruction::LoadRegisters(Register(b)),
             _ => {
-                bail!(
-                    "Unknown opcode at {:03x}: {:x}{:x}{:x}{:x}",
-                    state.ip,
-                    a,
-                    b,
-                    c,
-                    d
-                );
+                bail!("Unknown opcode at {:03x}: {:x}{:x}{:x}{:x}", ip, a, b, c, d);
             }
         };
 
         Ok(inst)
     }
 
-    fn execute(
-        &self,
-        s: &mut State,
-        mem: &mut Memory,
-        screen: &mut [u32],
-        timer: &mut Timer,
-    ) -> Result<()> {
+    // Whether the instruction can send ip somewhere other than the next
+    // instruction: jumps, calls, returns and skips.
+    fn is_control_flow(&self) -> bool {
+        match self {
+            Instruction::Return => true,
+            _ => self.jump_target(&State::default()).is_some(),
+        }
+    }
+
+    // Where the instruction sends ip when it doesn't fall through, for an
+    // instruction at state.ip. Returns have no target here as it lives on
+    // the stack.
+    fn jump_target(&self, state: &State) -> Option<Address> {
+        match *self {
+            Instruction::Jump(a) | Instruction::Call(a) => Some(a),
+            Instruction::JumpOffset(a) => Some(Address(a.0 + state.v[0] as u16)),
+            Instruction::SkipIfEqual(..)
+            | Instruction::SkipIfNotEqual(..)
+            | Instruction::SkipIfRegistersEqual(..)
+            | Instruction::SkipIfRegistersNotEqual(..)
+            | Instruction::SkipIfPressed(_)
+            | Instruction::SkipIfNotPressed(_) => Some(Address(state.ip + 4)),
+            _ => None,
+        }
+    }
+
+    fn execute(&self, emu: &mut Emulator) -> Result<()> {
+        let Emulator {
+            state: s,
+            memory: mem,
+            screen,
+            timer,
+            keypad,
+            quirks,
+            rng,
+            warn_draw_low,
+            warned_draws,
+            ..
+        } = emu;
         let mut ip = s.ip + 2;
         match *self {
             Instruction::ClearScreen => {
-                screen.fill(0xff000000);
+                screen.fill(false);
             }
-            Instruction::SysCall(_) => {
-                todo!();
+            // Machine code routines on the original hardware; nothing to run.
+            Instruction::SysCall(a) => {
+                bail!("SYS {} at {:03x} is not supported", a, s.ip);
             }
             Instruction::Return => {
-                ip = mem.stack[s.sp];
-                s.sp -= 1;
+                ip = mem.stack.pop()?;
             }
             Instruction::Jump(a) => {
                 ip = a.0;
             }
             Instruction::Call(a) => {
-                s.sp += 1;
-                mem.stack[s.sp] = ip;
+                mem.stack.push(ip)?;
                 ip = a.0;
             }
             Instruction::SkipIfEqual(r, c) => {
@@ -162,19 +210,35 @@ impl Instruction {
                     ip += 2;
                 }
             }
+            Instruction::SkipIfRegistersNotEqual(x, y) => {
+                if s.get(x) != s.get(y) {
+                    ip += 2;
+                }
+            }
             Instruction::SetImmediate(r, c) => {
                 s.set(r, c);
             }
+            // Never touches VF; a wrap is only recorded for debugging.
             Instruction::AddImmediate(r, c) => {
-                s.set(r, Constant(s.get(r).0.wrapping_add(c.0)));
+                let (val, overflowed) = s.get(r).0.overflowing_add(c.0);
+                s.set(r, Constant(val));
+                s.add_overflowed = overflowed;
             }
             Instruction::SetRegister(x, y) => {
                 s.set(x, s.get(y));
             }
+            Instruction::OrRegister(x, y) => {
+                let val = s.get(x).0 | s.get(y).0;
+                s.set(x, Constant(val));
+            }
             Instruction::AndRegister(x, y) => {
                 let val = s.get(x).0 & s.get(y).0;
                 s.set(x, Constant(val));
             }
+            Instruction::XorRegister(x, y) => {
+                let val = s.get(x).0 ^ s.get(y).0;
+                s.set(x, Constant(val));
+            }
             Instruction::AdcRegister(x, y) => {
                 let a = s.get(x);
                 let b = s.get(y);
@@ -189,43 +253,67 @@ impl Instruction {
                 s.set(x, Constant(a.0.wrapping_sub(b.0)));
                 s.set(VF, Constant(if c { 1 } else { 0 }));
             }
+            Instruction::ReverseSwbRegister(x, y) => {
+                let a = s.get(x);
+                let b = s.get(y);
+                let c = b.0.checked_sub(a.0).is_some();
+                s.set(x, Constant(b.0.wrapping_sub(a.0)));
+                s.set(VF, Constant(if c { 1 } else { 0 }));
+            }
+            // VF is written after the result so that shifting VF itself leaves
+            // the shifted-out bit there.
+            Instruction::ShrRegister(x, y) => {
+                let val = match quirks.shift {
+                    Behavior::Old => s.get(y).0,
+                    Behavior::New => s.get(x).0,
+                };
+                s.set(x, Constant(val >> 1));
+                s.set(VF, Constant(val & 1));
+            }
+            Instruction::ShlRegister(x, y) => {
+                let val = match quirks.shift {
+                    Behavior::Old => s.get(y).0,
+                    Behavior::New => s.get(x).0,
+                };
+                s.set(x, Constant(val << 1));
+                s.set(VF, Constant(val >> 7));
+            }
             Instruction::StoreAddress(a) => {
                 s.address = a;
             }
+            Instruction::JumpOffset(a) => {
+                ip = a.0 + s.v[0] as u16;
+            }
             Instruction::StoreRandom(r, c) => {
-                let val = rand::random::<u8>() & c.0;
+                let val = rng.next_u8() & c.0;
                 s.set(r, Constant(val))
             }
             Instruction::DrawSprite(x, y, n) => {
+                if *warn_draw_low && reads_low_memory(s.address.0, n.0) && warned_draws.insert(s.ip)
+                {
+                    eprintln!(
+                        "warning: DRW at {:03x} reads sprite data from {:03x}, below the \
+                         program and outside the font",
+                        s.ip, s.address.0
+                    );
+                }
                 let mut flipped_any_pixels_to_unset = false;
-                let x = s.get(x).0 as usize;
-                let y = s.get(y).0 as usize;
-                for row in 0..n.0 as usize {
+                // The starting position wraps but the sprite itself is clipped
+                // at the edges, so rows and columns past them are never visited.
+                let x = s.get(x).0 as usize % WIDTH;
+                let y = s.get(y).0 as usize % HEIGHT;
+                let rows = (n.0 as usize).min(HEIGHT - y);
+                let columns = (WIDTH - x).min(8);
+                for row in 0..rows {
                     let offset = x + (y + row) * WIDTH;
-                    let pixels = mem.mem[s.address.0 as usize + row];
-                    for bit in 0..8 {
-                        let sprite_bit = if (pixels & (1 << (7 - bit))) != 0 {
-                            1
-                        } else {
-                            0
-                        };
-                        let screen_bit = if screen[offset + bit as usize] == 0xffffffff {
-                            1
-                        } else {
-                            0
-                        };
-                        let new_bit = screen_bit ^ sprite_bit;
-                        if new_bit == 0 && screen_bit == 1 {
+                    let pixels = mem.read(s.address.0 as usize + row, quirks.memory_wrap)?;
+                    for bit in 0..columns {
+                        let sprite_on = pixels & (0x80 >> bit) != 0;
+                        let pixel = &mut screen[offset + bit];
+                        if sprite_on && *pixel {
                             flipped_any_pixels_to_unset = true;
                         }
-                        if new_bit == 0 {
-                            if screen_bit == 1 {
-                                flipped_any_pixels_to_unset = true;
-                            }
-                            screen[offset + bit as usize] = 0xFF000000;
-                        } else {
-                            screen[offset + bit as usize] = 0xffffffff;
-                        }
+                        *pixel ^= sprite_on;
                     }
                 }
                 s.set(
@@ -233,12 +321,32 @@ impl Instruction {
                     Constant(if flipped_any_pixels_to_unset { 1 } else { 0 }),
                 )
             }
-            Instruction::SkipIfPressed(_) => {
-                //
+            Instruction::SkipIfPressed(r) => {
+                if keypad.poll_pressed(s.get(r).0, quirks.key_debounce) {
+                    ip += 2;
+                }
             }
-            Instruction::SkipIfNotPressed(_) => {
-                ip += 2;
+            Instruction::SkipIfNotPressed(r) => {
+                if !keypad.is_down(s.get(r).0) {
+                    ip += 2;
+                }
             }
+            // Re-executes until a key has been delivered; timers keep running.
+            Instruction::WaitKeyPress(r) => match s.waiting_key {
+                None => match keypad.first_down() {
+                    Some(k) if !quirks.getkey_on_release => s.set(r, Constant(k)),
+                    Some(k) => {
+                        s.waiting_key = Some(k);
+                        ip = s.ip;
+                    }
+                    None => ip = s.ip,
+                },
+                Some(k) if keypad.is_down(k) => ip = s.ip,
+                Some(k) => {
+                    s.set(r, Constant(k));
+                    s.waiting_key = None;
+                }
+            },
             Instruction::SetFromDelay(r) => {
                 s.set(r, Constant(timer.delay_value));
             }
@@ -255,40 +363,109 @@ impl Instruction {
             }
             Instruction::StoreBCD(r) => {
                 let val = s.get(r).0;
+                let addr = s.address.0 as usize;
+                // Fail before the first digit rather than leave part written.
+                if !quirks.memory_wrap && addr + 2 >= mem.mem.len() {
+                    bail!("Write past the end of memory at {:04x}", addr + 2);
+                }
                 let h = val / 100;
-                mem.mem[s.address.0 as usize + 0] = h;
+                mem.write(addr, h, quirks.memory_wrap)?;
                 let t = (val - 100 * h) / 10;
-                mem.mem[s.address.0 as usize + 1] = t;
+                mem.write(addr + 1, t, quirks.memory_wrap)?;
                 let o = val - 100 * h - 10 * t;
-                mem.mem[s.address.0 as usize + 2] = o;
+                mem.write(addr + 2, o, quirks.memory_wrap)?;
             }
             Instruction::StoreRegisters(x) => {
                 for i in 0..x.0 {
-                    mem.mem[s.address.0 as usize + i as usize] = s.v[i as usize];
+                    let addr = s.address.0 as usize + i as usize;
+                    mem.write(addr, s.v[i as usize], quirks.memory_wrap)?;
                 }
             }
             Instruction::LoadRegisters(x) => {
                 for i in 0..x.0 {
-                    s.v[i as usize] = mem.mem[s.address.0 as usize + i as usize];
+                    let addr = s.address.0 as usize + i as usize;
+                    s.v[i as usize] = mem.read(addr, quirks.memory_wrap)?;
                 }
             }
-            _ => {
-                todo!("{:?}", *self);
-            }
         }
         s.ip = ip;
         Ok(())
     }
 }
 
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{:03X}", self.0)
+    }
+}
+
+impl std::fmt::Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "V{:X}", self.0)
+    }
+}
+
+impl std::fmt::Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{:02X}", self.0)
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Instruction::SysCall(a) => write!(f, "SYS {}", a),
+            Instruction::ClearScreen => write!(f, "CLS"),
+            Instruction::Return => write!(f, "RET"),
+            Instruction::Jump(a) => write!(f, "JP {}", a),
+            Instruction::Call(a) => write!(f, "CALL {}", a),
+            Instruction::SkipIfEqual(r, c) => write!(f, "SE {}, {}", r, c),
+            Instruction::SkipIfNotEqual(r, c) => write!(f, "SNE {}, {}", r, c),
+            Instruction::SkipIfRegistersEqual(x, y) => write!(f, "SE {}, {}", x, y),
+            Instruction::SetImmediate(r, c) => write!(f, "LD {}, {}", r, c),
+            Instruction::AddImmediate(r, c) => write!(f, "ADD {}, {}", r, c),
+            Instruction::SetRegister(x, y) => write!(f, "LD {}, {}", x, y),
+            Instruction::OrRegister(x, y) => write!(f, "OR {}, {}", x, y),
+            Instruction::AndRegister(x, y) => write!(f, "AND {}, {}", x, y),
+            Instruction::XorRegister(x, y) => write!(f, "XOR {}, {}", x, y),
+            Instruction::AdcRegister(x, y) => write!(f, "ADD {}, {}", x, y),
+            Instruction::SwbRegister(x, y) => write!(f, "SUB {}, {}", x, y),
+            Instruction::ShrRegister(x, y) => write!(f, "SHR {}, {}", x, y),
+            Instruction::ReverseSwbRegister(x, y) => write!(f, "SUBN {}, {}", x, y),
+            Instruction::ShlRegister(x, y) => write!(f, "SHL {}, {}", x, y),
+            Instruction::SkipIfRegistersNotEqual(x, y) => write!(f, "SNE {}, {}", x, y),
+            Instruction::StoreAddress(a) => write!(f, "LD I, {}", a),
+            Instruction::JumpOffset(a) => write!(f, "JP V0, {}", a),
+            Instruction::StoreRandom(r, c) => write!(f, "RND {}, {}", r, c),
+            Instruction::DrawSprite(x, y, n) => write!(f, "DRW {}, {}, {}", x, y, n.0),
+            Instruction::SkipIfPressed(r) => write!(f, "SKP {}", r),
+            Instruction::SkipIfNotPressed(r) => write!(f, "SKNP {}", r),
+            Instruction::SetFromDelay(r) => write!(f, "LD {}, DT", r),
+            Instruction::WaitKeyPress(r) => write!(f, "LD {}, K", r),
+            Instruction::SetToDelay(r) => write!(f, "LD DT, {}", r),
+            Instruction::SetToSound(r) => write!(f, "LD ST, {}", r),
+            Instruction::AddAddress(r) => write!(f, "ADD I, {}", r),
+            Instruction::SetAddressToSprite(r) => write!(f, "LD F, {}", r),
+            Instruction::StoreBCD(r) => write!(f, "LD B, {}", r),
+            Instruction::StoreRegisters(r) => write!(f, "LD [I], {}", r),
+            Instruction::LoadRegisters(r) => write!(f, "LD {}, [I]", r),
+        }
+    }
+}
+
 const VF: Register = Register(0xf);
 
 struct State {
     ip: u16,
     finished: bool,
     address: Address,
-    sp: usize,
     v: [u8; 16],
+    // Key seen going down during FX0A, delivered once it is released.
+    waiting_key: Option<u8>,
+    // Whether the last 7XNN wrapped past 0xFF.
+    add_overflowed: bool,
+    // Set by DXYN under the display wait quirk until the next 60Hz tick.
+    waiting_vblank: bool,
 }
 
 impl State {
@@ -307,8 +484,45 @@ impl Default for State {
             ip: 0x200,
             finished: false,
             address: Address(0),
-            sp: 0,
             v: [0; 16],
+            waiting_key: None,
+            add_overflowed: false,
+            waiting_vblank: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TimingMode {
+    /// The original loop: one instruction per iteration as fast as the host
+    /// allows, with timers ticking and the screen presented every 1/60s of
+    /// wall-clock time.
+    Legacy,
+    /// Timers tick every `cycles_per_tick` executed instructions and the wall
+    /// clock is ignored, so runs are as fast as the host allows and
+    /// reproducible.
+    Virtual { cycles_per_tick: u32 },
+    /// As virtual, but the window waits out the rest of each 1/60s after
+    /// presenting a frame, so programs run at `cycles_per_frame * 60`
+    /// instructions per second.
+    Frame { cycles_per_frame: u32 },
+}
+
+impl Default for TimingMode {
+    fn default() -> Self {
+        TimingMode::Frame {
+            cycles_per_frame: 10,
+        }
+    }
+}
+
+impl TimingMode {
+    // Instructions per 60Hz tick, unless ticks follow the wall clock.
+    fn cycles_per_tick(&self) -> Option<u32> {
+        match *self {
+            TimingMode::Legacy => None,
+            TimingMode::Virtual { cycles_per_tick } => Some(cycles_per_tick),
+            TimingMode::Frame { cycles_per_frame } => Some(cycles_per_frame),
         }
     }
 }
@@ -316,7 +530,9 @@ impl Default for State {
 struct Timer {
     delay_value: u8,
     sound_value: u8,
+    mode: TimingMode,
     last_update: Instant,
+    cycles: u32,
 }
 
 impl Default for Timer {
@@ -324,92 +540,2737 @@ impl Default for Timer {
         Timer {
             delay_value: 0,
             sound_value: 0,
+            mode: TimingMode::default(),
             last_update: Instant::now(),
+            cycles: 0,
         }
     }
 }
 
 impl Timer {
-    fn update(&mut self) -> bool {
-        let now = Instant::now();
-        let diff = now - self.last_update;
-        if diff.as_micros() >= 16600 {
-            if self.delay_value > 0 {
-                self.delay_value -= 1;
-            }
-            if self.sound_value > 0 {
-                self.sound_value -= 1;
-            }
-            self.last_update = now;
-            true
-        } else {
-            false
+    // Called once per executed instruction. Returns true when a 60Hz tick
+    // is due; the caller does the ticking.
+    fn due(&mut self) -> bool {
+        match self.mode.cycles_per_tick() {
+            None => {
+                let now = Instant::now();
+                let diff = now - self.last_update;
+                if diff.as_micros() >= 16600 {
+                    self.last_update = now;
+                    true
+                } else {
+                    false
+                }
+            }
+            Some(cycles_per_tick) => {
+                self.cycles += 1;
+                if self.cycles >= cycles_per_tick {
+                    self.cycles = 0;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.delay_value > 0 {
+            self.delay_value -= 1;
+        }
+        if self.sound_value > 0 {
+            self.sound_value -= 1;
+        }
+    }
+}
+
+struct Stack {
+    entries: Vec<u16>,
+    depth: usize,
+}
+
+impl Stack {
+    fn new(depth: usize) -> Self {
+        Stack {
+            entries: Vec::with_capacity(depth),
+            depth,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn push(&mut self, addr: u16) -> Result<()> {
+        if self.entries.len() == self.depth {
+            bail!("Stack overflow: more than {} nested calls", self.depth);
         }
+        self.entries.push(addr);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16> {
+        self.entries
+            .pop()
+            .ok_or_else(|| anyhow!("Stack underflow: return without a call"))
     }
 }
 
 struct Memory {
-    stack: [u16; 16],
+    stack: Stack,
     mem: [u8; 4096],
+    decode_cache: DecodeCache,
+    // Where load_rom put the program, and what to do about writes into it.
+    rom_range: std::ops::Range<usize>,
+    rom_writes: RomWrites,
+    // Addresses already warned about, to report each only once.
+    warned_rom_writes: HashSet<usize>,
+}
+
+// How writes by the program into its own loaded image are treated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RomWrites {
+    Allow,
+    Warn,
+    Trap,
+}
+
+// Decoded instructions by address, for the step loop and the debugger.
+// Every change to memory must invalidate the entries it touches, or clear
+// the cache for a bulk load.
+struct DecodeCache {
+    entries: [Option<Instruction>; 4096],
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        DecodeCache {
+            entries: [None; 4096],
+        }
+    }
+}
+
+impl DecodeCache {
+    // Drops the entries whose bytes include addr.
+    fn invalidate(&mut self, addr: u16) {
+        for ip in &[addr, addr.wrapping_sub(1)] {
+            if let Some(entry) = self.entries.get_mut(*ip as usize) {
+                *entry = None;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries = [None; 4096];
+    }
+}
+
+impl Memory {
+    // Decodes the instruction at ip, reusing an earlier decode unless
+    // memory there has changed since.
+    fn decode_at(&mut self, ip: u16) -> Result<Instruction> {
+        if let Some(Some(inst)) = self.decode_cache.entries.get(ip as usize) {
+            return Ok(*inst);
+        }
+        let [hi, lo] = self.fetch(ip)?;
+        let inst = Instruction::decode_bytes(ip, hi, lo)?;
+        // A successful fetch means ip is inside memory.
+        self.decode_cache.entries[ip as usize] = Some(inst);
+        Ok(inst)
+    }
+
+    // The two bytes of the instruction at ip.
+    fn fetch(&self, ip: u16) -> Result<[u8; 2]> {
+        match self.mem.get(ip as usize..ip as usize + 2) {
+            Some(word) => Ok([word[0], word[1]]),
+            None => bail!("Fetch past the end of memory at {:03x}", ip),
+        }
+    }
+
+    // Reads one byte, wrapping past the end like write.
+    fn read(&self, addr: usize, wrap: bool) -> Result<u8> {
+        match addr {
+            a if a < self.mem.len() => Ok(self.mem[a]),
+            a if wrap => Ok(self.mem[a % self.mem.len()]),
+            a => bail!("Read past the end of memory at {:04x}", a),
+        }
+    }
+
+    // Writes one byte. An address past the end wraps around to 0 when wrap
+    // is set and is an error otherwise.
+    fn write(&mut self, addr: usize, value: u8, wrap: bool) -> Result<()> {
+        let addr = match addr {
+            a if a < self.mem.len() => a,
+            a if wrap => a % self.mem.len(),
+            a => bail!("Write past the end of memory at {:04x}", a),
+        };
+        if self.rom_range.contains(&addr) {
+            match self.rom_writes {
+                RomWrites::Allow => {}
+                RomWrites::Warn => {
+                    if self.warned_rom_writes.insert(addr) {
+                        eprintln!("warning: write to {:03x}, inside the loaded ROM", addr);
+                    }
+                }
+                RomWrites::Trap => bail!("Write to {:03x}, inside the loaded ROM", addr),
+            }
+        }
+        self.mem[addr] = value;
+        self.decode_cache.invalidate(addr as u16);
+        Ok(())
+    }
 }
 
 impl Default for Memory {
     fn default() -> Self {
         Memory {
-            stack: [0; 16],
+            stack: Stack::new(16),
             mem: [0; 4096],
+            decode_cache: DecodeCache::default(),
+            rom_range: 0..0,
+            rom_writes: RomWrites::Allow,
+            warned_rom_writes: HashSet::new(),
         }
     }
 }
 
-fn main() -> Result<()> {
-    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-    let options = WindowOptions {
-        scale: Scale::X8,
-        ..WindowOptions::default()
-    };
+#[derive(Clone, Copy, Debug)]
+struct Quirks {
+    // FX0A delivers the key when it is released, as on the COSMAC VIP,
+    // rather than as soon as it goes down.
+    getkey_on_release: bool,
+    // 8XY6/8XYE shift VY into VX (Old) or shift VX in place (New).
+    shift: Behavior,
+    // DXYN waits for the next 60Hz boundary, as the VIP waited for vblank.
+    display_wait: bool,
+    // Writes past 0xFFF wrap around to 0 instead of stopping with an error.
+    memory_wrap: bool,
+    // EX9E reports a held key once per press instead of on every check.
+    key_debounce: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            getkey_on_release: true,
+            shift: Behavior::Old,
+            display_wait: false,
+            memory_wrap: false,
+            key_debounce: false,
+        }
+    }
+}
 
-    let mut window = Window::new("Chip8", WIDTH, HEIGHT, options)?;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StepEvent {
+    Executed,
+    Drew,
+    FrameComplete,
+    // Stalled without executing anything.
+    Waiting,
+}
 
-    //window.limit_update_rate(Some(Duration::from_micros(16600)));
-    window.limit_update_rate(None);
+impl std::fmt::Display for Quirks {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "  getkey_on_release: {}", self.getkey_on_release)?;
+        let shift = match self.shift {
+            Behavior::Old => "VY into VX",
+            Behavior::New => "VX in place",
+        };
+        writeln!(f, "  shift: {}", shift)?;
+        writeln!(f, "  display_wait: {}", self.display_wait)?;
+        writeln!(f, "  memory_wrap: {}", self.memory_wrap)?;
+        writeln!(f, "  key_debounce: {}", self.key_debounce)
+    }
+}
+
+// A four-character opcode pattern such as FX75 or 00FD. Hex digits must
+// match; X, Y and N stand for any digit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct OpcodePattern {
+    mask: u16,
+    value: u16,
+}
+
+impl OpcodePattern {
+    fn matches(&self, word: u16) -> bool {
+        word & self.mask == self.value
+    }
+}
+
+impl std::str::FromStr for OpcodePattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<OpcodePattern> {
+        if s.len() != 4 {
+            bail!(
+                "opcode pattern '{}' should be four characters, like FX75",
+                s
+            );
+        }
+        let mut pattern = OpcodePattern { mask: 0, value: 0 };
+        for c in s.chars() {
+            pattern.mask <<= 4;
+            pattern.value <<= 4;
+            match c {
+                'X' | 'x' | 'Y' | 'y' | 'N' | 'n' => {}
+                c => match c.to_digit(16) {
+                    Some(d) => {
+                        pattern.mask |= 0xf;
+                        pattern.value |= d as u16;
+                    }
+                    None => bail!(
+                        "'{}' in opcode pattern '{}' is not a hex digit, X, Y or N",
+                        c,
+                        s
+                    ),
+                },
+            }
+        }
+        Ok(pattern)
+    }
+}
+
+// What happens to an opcode the policy denies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Denied {
+    // Stop with an error.
+    Error,
+    // Step over it as if it were not there.
+    Skip,
+}
+
+// Opcodes a host refuses to run, for untrusted ROMs.
+#[derive(Clone, Debug)]
+struct OpcodePolicy {
+    denied: Vec<OpcodePattern>,
+    action: Denied,
+}
 
-    let mut timer = Timer::default();
-    let mut memory = Memory::default();
-    let mut state = State::default();
-
-    // Load ROM.
-    let rom = std::fs::read("roms/BLINKY")?;
-    memory.mem[0x200..0x200 + rom.len()].copy_from_slice(&rom);
-
-    // Load ROM font.
-    memory.mem[5 * 0x0..5 * 0x1].copy_from_slice(&[0xf0, 0x90, 0x90, 0x90, 0xf0]);
-    memory.mem[5 * 0x1..5 * 0x2].copy_from_slice(&[0x20, 0x60, 0x20, 0x20, 0x70]);
-    memory.mem[5 * 0x2..5 * 0x3].copy_from_slice(&[0xf0, 0x10, 0xf0, 0x80, 0xf0]);
-    memory.mem[5 * 0x3..5 * 0x4].copy_from_slice(&[0xf0, 0x10, 0xf0, 0x10, 0xf0]);
-    memory.mem[5 * 0x4..5 * 0x5].copy_from_slice(&[0x90, 0x90, 0xf0, 0x10, 0x10]);
-    memory.mem[5 * 0x5..5 * 0x6].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x10, 0xf0]);
-    memory.mem[5 * 0x6..5 * 0x7].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x90, 0xf0]);
-    memory.mem[5 * 0x7..5 * 0x8].copy_from_slice(&[0xf0, 0x10, 0x20, 0x40, 0x40]);
-    memory.mem[5 * 0x8..5 * 0x9].copy_from_slice(&[0xf0, 0x90, 0xf0, 0x90, 0xf0]);
-    memory.mem[5 * 0x9..5 * 0xa].copy_from_slice(&[0xf0, 0x90, 0xf0, 0x10, 0xf0]);
-    memory.mem[5 * 0xa..5 * 0xb].copy_from_slice(&[0xf0, 0x90, 0xf0, 0x90, 0x90]);
-    memory.mem[5 * 0xb..5 * 0xc].copy_from_slice(&[0xe0, 0x90, 0xe0, 0x90, 0xe0]);
-    memory.mem[5 * 0xc..5 * 0xd].copy_from_slice(&[0xf0, 0x80, 0x80, 0x80, 0xf0]);
-    memory.mem[5 * 0xd..5 * 0xe].copy_from_slice(&[0xe0, 0x90, 0x90, 0x90, 0xe0]);
-    memory.mem[5 * 0xe..5 * 0xf].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x80, 0xf0]);
-    memory.mem[5 * 0xf..5 * 0x10].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x80, 0x80]);
-
-    while window.is_open() && !window.is_key_down(Key::Escape) && !state.finished {
-        let redraw = timer.update();
-        let instruction = Instruction::decode(&state, &memory)?;
-        //println!("0x{:04x}: {:?}", state.ip, instruction);
-        instruction.execute(&mut state, &mut memory, &mut buffer, &mut timer)?;
-
-        if redraw {
-            window.update_with_buffer(&buffer, WIDTH, HEIGHT)?;
+impl Default for OpcodePolicy {
+    fn default() -> Self {
+        OpcodePolicy {
+            denied: Vec::new(),
+            action: Denied::Error,
         }
     }
+}
 
-    Ok(())
+impl OpcodePolicy {
+    fn denies(&self, word: u16) -> bool {
+        self.denied.iter().any(|p| p.matches(word))
+    }
+}
+
+#[derive(Clone)]
+struct Config {
+    timing: TimingMode,
+    quirks: Quirks,
+    stack_depth: usize,
+    seed: Option<u64>,
+    warn_draw_low: bool,
+    rom_writes: RomWrites,
+    policy: OpcodePolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            timing: TimingMode::default(),
+            quirks: Quirks::default(),
+            stack_depth: 16,
+            seed: None,
+            warn_draw_low: false,
+            rom_writes: RomWrites::Allow,
+            policy: OpcodePolicy::default(),
+        }
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.timing {
+            TimingMode::Legacy => writeln!(f, "timing: legacy, 60Hz wall clock")?,
+            TimingMode::Virtual { cycles_per_tick } => {
+                writeln!(f, "timing: virtual, {} cycles per tick", cycles_per_tick)?
+            }
+            TimingMode::Frame { cycles_per_frame } => writeln!(
+                f,
+                "timing: frame, {} cycles per 60Hz frame",
+                cycles_per_frame
+            )?,
+        }
+        writeln!(f, "stack depth: {}", self.stack_depth)?;
+        writeln!(f, "warn on low-memory draws: {}", self.warn_draw_low)?;
+        let rom_writes = match self.rom_writes {
+            RomWrites::Allow => "allow",
+            RomWrites::Warn => "warn",
+            RomWrites::Trap => "trap",
+        };
+        writeln!(f, "writes into the ROM image: {}", rom_writes)?;
+        if !self.policy.denied.is_empty() {
+            let action = match self.policy.action {
+                Denied::Error => "error",
+                Denied::Skip => "skip",
+            };
+            writeln!(
+                f,
+                "denied opcodes: {} ({})",
+                self.policy.denied.len(),
+                action
+            )?;
+        }
+        writeln!(f, "quirks:")?;
+        write!(f, "{}", self.quirks)?;
+        writeln!(
+            f,
+            "colors: on={:06x} off={:06x}",
+            ON_COLOR & 0xffffff,
+            OFF_COLOR & 0xffffff
+        )?;
+        match self.seed {
+            Some(seed) => writeln!(f, "rng seed: {}", seed),
+            None => writeln!(f, "rng seed: none (from entropy)"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Keypad {
+    down: [bool; 16],
+    // Keys EX9E has already reported during the current press.
+    reported: [bool; 16],
+}
+
+impl Keypad {
+    fn is_down(&self, key: u8) -> bool {
+        self.down[(key & 0xf) as usize]
+    }
+
+    fn set(&mut self, key: u8, down: bool) {
+        let key = (key & 0xf) as usize;
+        self.down[key] = down;
+        if !down {
+            self.reported[key] = false;
+        }
+    }
+
+    // Whether EX9E sees the key as pressed. With debounce a held key is
+    // reported once, and again only after it has been released.
+    fn poll_pressed(&mut self, key: u8, debounce: bool) -> bool {
+        let key = (key & 0xf) as usize;
+        if !debounce {
+            return self.down[key];
+        }
+        let fresh = self.down[key] && !self.reported[key];
+        self.reported[key] |= fresh;
+        fresh
+    }
+
+    fn first_down(&self) -> Option<u8> {
+        self.down.iter().position(|&d| d).map(|k| k as u8)
+    }
+}
+
+// Host keys for the hex keypad, indexed by CHIP-8 key.
+const KEYMAP: [Key; 16] = [
+    Key::X,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Q,
+    Key::W,
+    Key::E,
+    Key::A,
+    Key::S,
+    Key::D,
+    Key::Z,
+    Key::C,
+    Key::Key4,
+    Key::R,
+    Key::F,
+    Key::V,
+];
+
+// Whether a sprite of `rows` bytes at `address` overlaps the interpreter
+// area between the font and the program, which no ROM initializes.
+fn reads_low_memory(address: u16, rows: u8) -> bool {
+    let start = address as usize;
+    let end = start + rows as usize;
+    start < 0x200 && end > FONT_SIZE
+}
+
+// 64-bit FNV-1a. Unlike the std hasher its output is fixed across platforms
+// and releases, so it can be persisted.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Cheap guess at whether a file is really a CHIP-8 program, based on how it
+// starts. Advisory only; returns a description of what looks wrong.
+fn check_rom(rom: &[u8]) -> Option<String> {
+    if rom.len() < 2 {
+        return Some(format!("ROM is only {} bytes long", rom.len()));
+    }
+    if rom[0] == 0 && rom[1] == 0 {
+        return Some("ROM starts with 0000".to_string());
+    }
+    let head = &rom[..rom.len().min(16)];
+    if head.len() >= 8 && head.iter().all(|&b| b == head[0]) {
+        return Some(format!(
+            "ROM starts with a run of {} {:02x} bytes",
+            head.len(),
+            head[0]
+        ));
+    }
+    if let Err(e) = Instruction::decode_bytes(0x200, rom[0], rom[1]) {
+        return Some(e.to_string());
+    }
+    None
+}
+
+// Where CXNN gets its random bytes. The emulator holds one so a host or a
+// test can substitute a fixed or recorded sequence.
+trait RandomSource {
+    fn next_u8(&mut self) -> u8;
+    // Starts over for a reset. A fixed sequence can ignore the seed and
+    // rewind to its start.
+    fn reseed(&mut self, seed: u64);
+}
+
+impl RandomSource for StdRng {
+    fn next_u8(&mut self) -> u8 {
+        self.gen()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        *self = StdRng::seed_from_u64(seed);
+    }
+}
+
+// Hooks called around every executed instruction, for tracers and other
+// instrumentation. `ip` is the address the instruction was fetched from.
+trait ExecObserver {
+    fn before(&mut self, _ip: u16, _instruction: &Instruction, _emu: &Emulator) {}
+    fn after(&mut self, _ip: u16, _instruction: &Instruction, _emu: &Emulator) {}
+}
+
+struct Tracer;
+
+impl ExecObserver for Tracer {
+    fn before(&mut self, ip: u16, instruction: &Instruction, _emu: &Emulator) {
+        println!("0x{:04x}: {}", ip, instruction);
+    }
+}
+
+struct Emulator {
+    state: State,
+    memory: Memory,
+    screen: Vec<bool>,
+    timer: Timer,
+    keypad: Keypad,
+    quirks: Quirks,
+    rng: Box<dyn RandomSource>,
+    cycles: u64,
+    frames: u64,
+    // Identifies the loaded program in snapshots.
+    rom_hash: u64,
+    // The loaded program, for reset. Empty when started from a snapshot.
+    rom: Vec<u8>,
+    // What the rng was seeded with, so a reset can repeat the run.
+    seed: u64,
+    warn_draw_low: bool,
+    // DRW instructions already warned about, to report each only once.
+    warned_draws: HashSet<u16>,
+    observer: Option<Box<dyn ExecObserver>>,
+    policy: OpcodePolicy,
+    // Memory held at fixed values, restored at the end of every frame.
+    cheats: BTreeMap<u16, u8>,
+}
+
+impl Emulator {
+    fn new(config: &Config) -> Self {
+        let seed = config.seed.unwrap_or_else(random);
+        let mut emulator = Emulator {
+            state: State::default(),
+            memory: Memory {
+                stack: Stack::new(config.stack_depth),
+                rom_writes: config.rom_writes,
+                ..Memory::default()
+            },
+            screen: vec![false; WIDTH * HEIGHT],
+            timer: Timer {
+                mode: config.timing,
+                ..Timer::default()
+            },
+            keypad: Keypad::default(),
+            quirks: config.quirks,
+            rng: Box::new(StdRng::seed_from_u64(seed)),
+            cycles: 0,
+            frames: 0,
+            rom_hash: 0,
+            rom: Vec::new(),
+            seed,
+            warn_draw_low: config.warn_draw_low,
+            warned_draws: HashSet::new(),
+            observer: None,
+            policy: config.policy.clone(),
+            cheats: BTreeMap::new(),
+        };
+
+        // Load ROM font.
+        let memory = &mut emulator.memory;
+        memory.mem[5 * 0x0..5 * 0x1].copy_from_slice(&[0xf0, 0x90, 0x90, 0x90, 0xf0]);
+        memory.mem[5 * 0x1..5 * 0x2].copy_from_slice(&[0x20, 0x60, 0x20, 0x20, 0x70]);
+        memory.mem[5 * 0x2..5 * 0x3].copy_from_slice(&[0xf0, 0x10, 0xf0, 0x80, 0xf0]);
+        memory.mem[5 * 0x3..5 * 0x4].copy_from_slice(&[0xf0, 0x10, 0xf0, 0x10, 0xf0]);
+        memory.mem[5 * 0x4..5 * 0x5].copy_from_slice(&[0x90, 0x90, 0xf0, 0x10, 0x10]);
+        memory.mem[5 * 0x5..5 * 0x6].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x10, 0xf0]);
+        memory.mem[5 * 0x6..5 * 0x7].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x90, 0xf0]);
+        memory.mem[5 * 0x7..5 * 0x8].copy_from_slice(&[0xf0, 0x10, 0x20, 0x40, 0x40]);
+        memory.mem[5 * 0x8..5 * 0x9].copy_from_slice(&[0xf0, 0x90, 0xf0, 0x90, 0xf0]);
+        memory.mem[5 * 0x9..5 * 0xa].copy_from_slice(&[0xf0, 0x90, 0xf0, 0x10, 0xf0]);
+        memory.mem[5 * 0xa..5 * 0xb].copy_from_slice(&[0xf0, 0x90, 0xf0, 0x90, 0x90]);
+        memory.mem[5 * 0xb..5 * 0xc].copy_from_slice(&[0xe0, 0x90, 0xe0, 0x90, 0xe0]);
+        memory.mem[5 * 0xc..5 * 0xd].copy_from_slice(&[0xf0, 0x80, 0x80, 0x80, 0xf0]);
+        memory.mem[5 * 0xd..5 * 0xe].copy_from_slice(&[0xe0, 0x90, 0x90, 0x90, 0xe0]);
+        memory.mem[5 * 0xe..5 * 0xf].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x80, 0xf0]);
+        memory.mem[5 * 0xf..5 * 0x10].copy_from_slice(&[0xf0, 0x80, 0xf0, 0x80, 0x80]);
+
+        emulator
+    }
+
+    fn load_rom(&mut self, rom: &[u8]) -> Result<()> {
+        if 0x200 + rom.len() > self.memory.mem.len() {
+            bail!("ROM is too large: {} bytes", rom.len());
+        }
+        if let Some(warning) = check_rom(rom) {
+            eprintln!(
+                "warning: {}; this may not be a CHIP-8 ROM, or it may need a different \
+                 interpreter or quirk settings",
+                warning
+            );
+        }
+        self.memory.mem[0x200..0x200 + rom.len()].copy_from_slice(rom);
+        self.memory.decode_cache.clear();
+        self.memory.rom_range = 0x200..0x200 + rom.len();
+        self.rom_hash = fnv1a(rom);
+        self.rom = rom.to_vec();
+        Ok(())
+    }
+
+    // Starts the loaded program over: registers, stack, timers, display and
+    // memory above the font go back to how they were after load_rom. The
+    // random source is reseeded with the same seed when keep_seed is set, so
+    // the fresh run repeats the first one, and with a new one otherwise.
+    // Without a ROM (after starting from a snapshot) program memory is left
+    // as it is.
+    fn reset(&mut self, keep_seed: bool) {
+        self.state = State::default();
+        self.memory.stack = Stack::new(self.memory.stack.depth);
+        if !self.rom.is_empty() {
+            self.memory.mem[0x200..].iter_mut().for_each(|b| *b = 0);
+            self.memory.mem[0x200..0x200 + self.rom.len()].copy_from_slice(&self.rom);
+            self.memory.decode_cache.clear();
+        }
+        self.screen.iter_mut().for_each(|p| *p = false);
+        self.timer = Timer {
+            mode: self.timer.mode,
+            ..Timer::default()
+        };
+        if !keep_seed {
+            self.seed = random();
+        }
+        self.rng.reseed(self.seed);
+        self.cycles = 0;
+        self.frames = 0;
+        self.warned_draws.clear();
+    }
+
+    // Replaces where CXNN gets its bytes. Only tests do so far.
+    #[allow(dead_code)]
+    fn set_random_source(&mut self, rng: Box<dyn RandomSource>) {
+        self.rng = rng;
+    }
+
+    fn set_observer(&mut self, observer: Option<Box<dyn ExecObserver>>) {
+        self.observer = observer;
+    }
+
+    fn set_key(&mut self, key: u8, down: bool) {
+        self.keypad.set(key, down);
+    }
+
+    fn delay_timer(&self) -> u8 {
+        self.timer.delay_value
+    }
+
+    fn set_delay_timer(&mut self, value: u8) {
+        self.timer.delay_value = value;
+    }
+
+    fn sound_timer(&self) -> u8 {
+        self.timer.sound_value
+    }
+
+    fn set_sound_timer(&mut self, value: u8) {
+        self.timer.sound_value = value;
+    }
+
+    fn last_add_overflowed(&self) -> bool {
+        self.state.add_overflowed
+    }
+
+    fn set_register(&mut self, r: Register, value: u8) {
+        self.state.set(r, Constant(value));
+    }
+
+    fn set_address(&mut self, a: Address) {
+        self.state.address = a;
+    }
+
+    // For the debugger, so never trapped as a write into the ROM.
+    fn write_memory(&mut self, addr: u16, value: u8) -> Result<()> {
+        match self.memory.mem.get_mut(addr as usize) {
+            Some(byte) => *byte = value,
+            None => bail!("address {:03x} is outside memory", addr),
+        }
+        self.memory.decode_cache.invalidate(addr);
+        Ok(())
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> bool {
+        self.screen[x + y * WIDTH]
+    }
+
+    // The display as rows of pixels, top row first.
+    fn display_grid(&self) -> Vec<Vec<bool>> {
+        self.screen.chunks(WIDTH).map(|row| row.to_vec()).collect()
+    }
+
+    // The display as rows of '#' (on) and '.' (off).
+    fn render_ascii(&self) -> String {
+        let mut out = String::with_capacity((WIDTH + 1) * HEIGHT);
+        for row in self.display_grid() {
+            out.extend(row.iter().map(|&on| if on { '#' } else { '.' }));
+            out.push('\n');
+        }
+        out
+    }
+
+    // FNV-1a over the resolution and one byte per pixel. Stable across
+    // platforms, so expected values can be written down in tests.
+    fn screen_hash(&self) -> u64 {
+        let mut bytes = Vec::with_capacity(4 + self.screen.len());
+        bytes.extend_from_slice(&(WIDTH as u16).to_le_bytes());
+        bytes.extend_from_slice(&(HEIGHT as u16).to_le_bytes());
+        bytes.extend(self.screen.iter().map(|&on| on as u8));
+        fnv1a(&bytes)
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        self.screen[x + y * WIDTH] = on;
+    }
+
+    // Executes one instruction, ticking the timers first when a 60Hz tick
+    // is due. A draw on the step that closes a frame is reported as
+    // FrameComplete, since the frame gets presented anyway.
+    fn step(&mut self) -> Result<StepEvent> {
+        let mut frame_complete = self.timer.due();
+        if frame_complete {
+            let released = self.state.waiting_vblank;
+            self.tick_timers_once();
+            if released {
+                return Ok(StepEvent::FrameComplete);
+            }
+        }
+        let event = self.step_cpu()?;
+        // Counted time gets a draw held for the display to the next 60Hz
+        // boundary at once, as await_tick does; in real time execution
+        // stalls until it arrives. A draw on the step that ticked is already
+        // at the start of a frame.
+        if self.state.waiting_vblank && self.timer.mode.cycles_per_tick().is_some() {
+            if frame_complete {
+                self.state.waiting_vblank = false;
+            } else {
+                frame_complete = self.await_tick() == StepEvent::FrameComplete;
+            }
+        }
+        Ok(if frame_complete {
+            StepEvent::FrameComplete
+        } else {
+            event
+        })
+    }
+
+    // Executes one instruction and leaves the timers alone, for hosts that
+    // tick them on their own schedule with tick_timers_once. Under display
+    // wait a draw then holds execution until the next tick.
+    fn step_cpu(&mut self) -> Result<StepEvent> {
+        if self.state.waiting_vblank {
+            return Ok(StepEvent::Waiting);
+        }
+        if self.skip_denied()? {
+            self.state.ip += 2;
+            self.cycles += 1;
+            return Ok(StepEvent::Executed);
+        }
+        let instruction = self.memory.decode_at(self.state.ip)?;
+        match self.observer.take() {
+            None => instruction.execute(self)?,
+            Some(mut observer) => {
+                let ip = self.state.ip;
+                observer.before(ip, &instruction, self);
+                let result = instruction.execute(self);
+                observer.after(ip, &instruction, self);
+                self.observer = Some(observer);
+                result?
+            }
+        }
+        self.cycles += 1;
+        let event = match instruction {
+            Instruction::ClearScreen => StepEvent::Drew,
+            Instruction::DrawSprite(..) => {
+                self.state.waiting_vblank = self.quirks.display_wait;
+                StepEvent::Drew
+            }
+            _ => StepEvent::Executed,
+        };
+        Ok(event)
+    }
+
+    // One 60Hz tick: the delay and sound timers count down, a draw held for
+    // the display is released, and cheats are reapplied.
+    fn tick_timers_once(&mut self) {
+        self.timer.tick();
+        self.state.waiting_vblank = false;
+        self.frames += 1;
+        self.apply_cheats();
+    }
+
+    fn apply_cheats(&mut self) {
+        for (&addr, &value) in &self.cheats {
+            self.memory.mem[addr as usize] = value;
+            self.memory.decode_cache.invalidate(addr);
+        }
+    }
+
+    // Holds addr at value from now on, until the cheat is removed.
+    fn add_cheat(&mut self, addr: u16, value: u8) {
+        self.cheats.insert(addr, value);
+        self.apply_cheats();
+    }
+
+    // Checks the opcode at ip against the policy. A denied one is an error,
+    // or is reported back to be stepped over.
+    fn skip_denied(&self) -> Result<bool> {
+        if self.policy.denied.is_empty() {
+            return Ok(false);
+        }
+        let ip = self.state.ip;
+        let word = u16::from_be_bytes(self.memory.fetch(ip)?);
+        if !self.policy.denies(word) {
+            return Ok(false);
+        }
+        match self.policy.action {
+            Denied::Error => bail!("Opcode {:04x} at {:03x} is denied", word, ip),
+            Denied::Skip => Ok(true),
+        }
+    }
+
+    // A jump to itself is how most programs stop for good.
+    fn is_halted(&mut self) -> bool {
+        match self.memory.decode_at(self.state.ip) {
+            Ok(inst @ Instruction::Jump(_)) | Ok(inst @ Instruction::JumpOffset(_)) => {
+                inst.jump_target(&self.state) == Some(Address(self.state.ip))
+            }
+            _ => false,
+        }
+    }
+
+    fn cycles_executed(&self) -> u64 {
+        self.cycles
+    }
+
+    // Frames of emulated time so far: the number of 60Hz timer ticks. That
+    // includes ticks a host applies with tick_timers_once, and wall-clock
+    // ticks, so it need not be the instruction count over cycles per tick.
+    fn emulated_frame(&self) -> u64 {
+        self.frames
+    }
+
+    // Instructions left before the next 60Hz boundary, when timing is
+    // counted in instructions rather than wall-clock time.
+    fn cycles_remaining_in_frame(&self) -> Option<u32> {
+        let cycles_per_tick = self.timer.mode.cycles_per_tick()?;
+        Some(cycles_per_tick.saturating_sub(self.timer.cycles))
+    }
+
+    // Base CHIP-8 is partial while any of its opcodes are missing. The
+    // extensions are compiled in by their cargo features.
+    fn capabilities() -> Capabilities {
+        let extension = |enabled| {
+            if enabled {
+                Support::Partial
+            } else {
+                Support::No
+            }
+        };
+        Capabilities {
+            chip8: if CHIP8_UNIMPLEMENTED.is_empty() {
+                Support::Yes
+            } else {
+                Support::Partial
+            },
+            superchip: extension(cfg!(feature = "superchip")),
+            xochip: extension(cfg!(feature = "xochip")),
+            unimplemented: CHIP8_UNIMPLEMENTED,
+        }
+    }
+
+    // Whether nothing can happen before the next 60Hz tick other than a key
+    // arriving: FX0A is waiting, the program jumps to itself, or DXYN is
+    // holding for the display.
+    fn is_blocked(&mut self) -> bool {
+        let waiting_key = matches!(
+            self.memory.decode_at(self.state.ip),
+            Ok(Instruction::WaitKeyPress(_))
+        );
+        waiting_key || self.state.waiting_vblank || self.is_halted()
+    }
+
+    // Moves a blocked program on to the next 60Hz tick without spinning.
+    // Counted time jumps straight there, as if the blocked instruction had
+    // run out the frame; wall-clock time is slept through. The window loop
+    // does not call it under legacy timing, which keeps the old spin. Keys
+    // are read as each frame is presented, so one pressed meanwhile is seen
+    // on the first step of the next frame.
+    fn await_tick(&mut self) -> StepEvent {
+        match self.cycles_remaining_in_frame() {
+            Some(remaining) => {
+                self.cycles += remaining as u64;
+                self.timer.cycles = 0;
+                self.tick_timers_once();
+                StepEvent::FrameComplete
+            }
+            None => {
+                let tick = self.timer.last_update + Duration::from_micros(16600);
+                let now = Instant::now();
+                if tick > now {
+                    std::thread::sleep(tick - now);
+                }
+                StepEvent::Waiting
+            }
+        }
+    }
+}
+
+// CHIP-8 opcodes that decode but stop the emulator with an error when
+// executed. A test runs every base opcode to keep this in step with execute.
+const CHIP8_UNIMPLEMENTED: &[&str] = &["0NNN"];
+
+// How much of an instruction set this build runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Support {
+    Yes,
+    Partial,
+    No,
+}
+
+impl std::fmt::Display for Support {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Support::Yes => write!(f, "yes"),
+            Support::Partial => write!(f, "partial"),
+            Support::No => write!(f, "no"),
+        }
+    }
+}
+
+// What this build can run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Capabilities {
+    chip8: Support,
+    superchip: Support,
+    xochip: Support,
+    unimplemented: &'static [&'static str],
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "CHIP-8: {}", self.chip8)?;
+        writeln!(f, "SUPER-CHIP: {}", self.superchip)?;
+        writeln!(f, "XO-CHIP: {}", self.xochip)?;
+        if !self.unimplemented.is_empty() {
+            writeln!(f, "not yet implemented: {}", self.unimplemented.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// Decides which steps hand the screen to the window. Frames are always
+// presented when they complete; with present_on_draw the first draw of a
+// frame is shown early as well, cutting input-to-screen latency. A nonzero
+// settle waits until that many non-drawing instructions follow the draw, so
+// a burst of DXYNs is shown whole rather than half drawn.
+#[derive(Default)]
+struct Presenter {
+    present_on_draw: bool,
+    settle: u32,
+    presented_this_frame: bool,
+    // Instructions since the last unpresented draw, if there is one.
+    since_draw: Option<u32>,
+}
+
+impl Presenter {
+    fn should_present(&mut self, event: StepEvent) -> bool {
+        match event {
+            StepEvent::FrameComplete => {
+                self.presented_this_frame = false;
+                self.since_draw = None;
+                return true;
+            }
+            StepEvent::Drew if self.present_on_draw && !self.presented_this_frame => {
+                self.since_draw = Some(0);
+            }
+            StepEvent::Executed => {
+                if let Some(n) = &mut self.since_draw {
+                    *n += 1;
+                }
+            }
+            _ => {}
+        }
+        match self.since_draw {
+            Some(n) if n >= self.settle => {
+                self.presented_this_frame = true;
+                self.since_draw = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// Builds the emulator from a ROM or a snapshot. Also returns where the
+// snapshot hotkeys save to.
+fn start(options: &Options, config: &Config) -> Result<(Emulator, String)> {
+    if options.verbose {
+        print!("{}", config);
+    }
+    let mut emulator = Emulator::new(config);
+    if options.trace {
+        emulator.set_observer(Some(Box::new(Tracer)));
+    }
+    let state_path = match &options.load_state {
+        Some(path) => {
+            let data = std::fs::read(path)?;
+            if let Some(rom) = &options.rom {
+                if fnv1a(&std::fs::read(rom)?) != snapshot::rom_hash(&data)? {
+                    eprintln!("warning: {} was not saved from {}", path, rom);
+                }
+            }
+            snapshot::restore(&mut emulator, &data)?;
+            path.clone()
+        }
+        None if options.builtin.is_some() => {
+            let name = options.builtin.as_deref().unwrap();
+            let rom = builtins::find(name).ok_or_else(|| {
+                anyhow!(
+                    "no built-in ROM '{}'; try one of {}",
+                    name,
+                    builtins::names().join(", ")
+                )
+            })?;
+            emulator.load_rom(rom)?;
+            format!("{}.state", name)
+        }
+        None => {
+            let rom_path = options.rom.as_deref().unwrap_or("roms/BLINKY");
+            let rom = std::fs::read(rom_path)?;
+            emulator.load_rom(&rom)?;
+            format!("{}.state", rom_path)
+        }
+    };
+    Ok((emulator, state_path))
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("disasm") {
+        let path = args
+            .get(1)
+            .ok_or_else(|| anyhow!("usage: chip8 disasm <rom>"))?;
+        let rom = std::fs::read(path)?;
+        print!("{}", disasm::disassemble(&rom));
+        return Ok(());
+    }
+    if args.first().map(String::as_str) == Some("diff-roms") {
+        if args.len() < 3 {
+            bail!("usage: chip8 diff-roms <a> <b> [options]");
+        }
+        let options = options::parse(&args[3..])?;
+        let same = headless::diff_roms(&args[1], &args[2], &options)?;
+        std::process::exit(if same { 0 } else { 1 });
+    }
+
+    let options = options::parse(&args)?;
+    if options.analyze {
+        let path = options
+            .rom
+            .as_deref()
+            .ok_or_else(|| anyhow!("--analyze needs a rom"))?;
+        print!("{}", disasm::analyze(&std::fs::read(path)?));
+        return Ok(());
+    }
+    if options.capabilities {
+        print!("{}", Emulator::capabilities());
+        return Ok(());
+    }
+    if options.builtin.as_deref() == Some("list") {
+        for name in builtins::names() {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    if options.suggest_speed {
+        return headless::suggest_speed(&options);
+    }
+    if options.headless {
+        if headless::run(&options)? == headless::Stop::TimedOut {
+            std::process::exit(headless::TIMED_OUT_STATUS);
+        }
+        return Ok(());
+    }
+    let keymap = match &options.keymap {
+        Some(path) => keymap::load(path)?,
+        None => Keymap::default(),
+    };
+    let (mut emulator, state_path) = start(&options, &options.config)?;
+
+    let result = run_window(&options, &mut emulator, &keymap, &state_path);
+    shutdown(&options, &emulator, result)
+}
+
+// Runs the emulator in a window until it is closed, Escape is pressed, the
+// program finishes or something fails.
+fn run_window(
+    options: &Options,
+    emulator: &mut Emulator,
+    keymap: &Keymap,
+    state_path: &str,
+) -> Result<()> {
+    let window_options = WindowOptions {
+        scale: Scale::X8,
+        ..WindowOptions::default()
+    };
+
+    let mut window = Window::new("Chip8", WIDTH, HEIGHT, window_options)?;
+
+    //window.limit_update_rate(Some(Duration::from_micros(16600)));
+    window.limit_update_rate(None);
+
+    let mut debugger = if options.debug {
+        Some(Debugger::new())
+    } else {
+        None
+    };
+
+    let mut buffer = vec![OFF_COLOR; WIDTH * HEIGHT];
+    let mut title = String::new();
+    let mut presenter = Presenter {
+        present_on_draw: options.present_on_draw,
+        settle: options.settle,
+        ..Presenter::default()
+    };
+    let mut next_frame = Instant::now();
+
+    while window.is_open() && !window.is_key_down(Key::Escape) && !emulator.state.finished {
+        if let Some(debugger) = &mut debugger {
+            if debugger.should_break(emulator) {
+                if !debugger.prompt(emulator)? {
+                    break;
+                }
+                present(&mut window, emulator, &mut buffer, options)?;
+                read_keys(&window, emulator, &keymap.pad);
+            }
+        }
+        let mut event = emulator.step()?;
+        // The legacy loop spins through a blocked program as it always did.
+        if event != StepEvent::FrameComplete
+            && emulator.timer.mode != TimingMode::Legacy
+            && emulator.is_blocked()
+        {
+            event = emulator.await_tick();
+        }
+        if presenter.should_present(event) {
+            present(&mut window, emulator, &mut buffer, options)?;
+            if let (StepEvent::FrameComplete, TimingMode::Frame { .. }) =
+                (event, emulator.timer.mode)
+            {
+                next_frame = pace(next_frame);
+            }
+            read_keys(&window, emulator, &keymap.pad);
+            let pressed = |key| window.is_key_pressed(key, KeyRepeat::No);
+            let down = |key| window.is_key_down(key);
+            if keymap.controls.save_state.fired(pressed, down) {
+                std::fs::write(state_path, snapshot::save(emulator))?;
+            }
+            if keymap.controls.reset.fired(pressed, down) {
+                emulator.reset(options.config.seed.is_some());
+            }
+            if keymap.controls.load_state.fired(pressed, down) {
+                // A bad snapshot leaves the emulator as it was, so play goes on.
+                let loaded = std::fs::read(state_path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|data| snapshot::restore(emulator, &data));
+                if let Err(e) = loaded {
+                    eprintln!("warning: cannot load {}: {}", state_path, e);
+                }
+            }
+            if options.inspect {
+                let new_title = inspect_title(&window, emulator);
+                if new_title != title {
+                    window.set_title(&new_title);
+                    title = new_title;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Everything that has to happen however the window loop ended, including
+// on an error. Returns how the run ended; a failure here only takes its
+// place when the run itself succeeded.
+fn shutdown(options: &Options, emulator: &Emulator, result: Result<()>) -> Result<()> {
+    let saved = match &options.autosave {
+        Some(path) => std::fs::write(path, snapshot::save(emulator))
+            .with_context(|| format!("autosaving to {}", path)),
+        None => Ok(()),
+    };
+    match (result, saved) {
+        (Err(e), Err(save_error)) => {
+            eprintln!("warning: {:#}", save_error);
+            Err(e)
+        }
+        (result, saved) => result.and(saved),
+    }
+}
+
+// Sleeps until `deadline`, one 1/60s frame after the last. Returns the
+// deadline for the next frame, restarting from now if the host fell behind.
+fn pace(deadline: Instant) -> Instant {
+    let frame = Duration::from_micros(16600);
+    let now = Instant::now();
+    if deadline > now {
+        std::thread::sleep(deadline - now);
+        deadline + frame
+    } else {
+        now + frame
+    }
+}
+
+// Alpha given to off pixels. The window ignores alpha, so this only shows
+// up where the buffer is composited or written out as an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OffAlpha {
+    Opaque,
+    Transparent,
+}
+
+fn to_argb(screen: &[bool], buffer: &mut [u32], off_alpha: OffAlpha) {
+    let off = match off_alpha {
+        OffAlpha::Opaque => OFF_COLOR,
+        OffAlpha::Transparent => OFF_COLOR & 0x00ffffff,
+    };
+    for (pixel, &on) in buffer.iter_mut().zip(screen) {
+        *pixel = if on { ON_COLOR } else { off };
+    }
+}
+
+// Whether --show-sound should mark the screen, given ST.
+fn sound_indicator_visible(sound_value: u8) -> bool {
+    sound_value > 0
+}
+
+// Paints a small block in the bottom-right corner over the display.
+fn draw_sound_indicator(buffer: &mut [u32]) {
+    for y in HEIGHT - 2..HEIGHT {
+        for x in WIDTH - 4..WIDTH {
+            buffer[x + y * WIDTH] = SOUND_COLOR;
+        }
+    }
+}
+
+// Which part of the window --sound-flash inverts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FlashExtent {
+    Border,
+    Screen,
+}
+
+// A visual cue for the sound timer: pixels in the extent move `percent` of
+// the way towards their inverse.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SoundFlash {
+    extent: FlashExtent,
+    percent: u8,
+}
+
+impl std::str::FromStr for SoundFlash {
+    type Err = anyhow::Error;
+
+    // `border` or `screen`, optionally followed by `:PERCENT`.
+    fn from_str(s: &str) -> Result<SoundFlash> {
+        let mut parts = s.splitn(2, ':');
+        let extent = match parts.next() {
+            Some("border") => FlashExtent::Border,
+            Some("screen") => FlashExtent::Screen,
+            _ => bail!("expected border or screen, got '{}'", s),
+        };
+        let percent = match parts.next() {
+            Some(n) => n.parse()?,
+            None => 100,
+        };
+        if percent == 0 || percent > 100 {
+            bail!("flash strength must be 1-100 percent, got {}", percent);
+        }
+        Ok(SoundFlash { extent, percent })
+    }
+}
+
+// Applies the flash to the window buffer only; the emulated display is
+// untouched, so the next present draws it as normal.
+fn apply_sound_flash(buffer: &mut [u32], flash: SoundFlash) {
+    let percent = flash.percent as u32;
+    let blend = |c: u32| (c * (100 - percent) + (0xff - c) * percent) / 100;
+    for (i, pixel) in buffer.iter_mut().enumerate() {
+        let (x, y) = (i % WIDTH, i / WIDTH);
+        let on_border = x == 0 || y == 0 || x == WIDTH - 1 || y == HEIGHT - 1;
+        if flash.extent == FlashExtent::Border && !on_border {
+            continue;
+        }
+        let (r, g, b) = ((*pixel >> 16) & 0xff, (*pixel >> 8) & 0xff, *pixel & 0xff);
+        *pixel = (*pixel & 0xff000000) | blend(r) << 16 | blend(g) << 8 | blend(b);
+    }
+}
+
+fn present(
+    window: &mut Window,
+    emulator: &Emulator,
+    buffer: &mut [u32],
+    options: &Options,
+) -> Result<()> {
+    to_argb(&emulator.screen, buffer, options.off_alpha);
+    if sound_indicator_visible(emulator.sound_timer()) {
+        if let Some(flash) = options.sound_flash {
+            apply_sound_flash(buffer, flash);
+        }
+        if options.show_sound {
+            draw_sound_indicator(buffer);
+        }
+    }
+    window.update_with_buffer(buffer, WIDTH, HEIGHT)?;
+    Ok(())
+}
+
+// Maps a mouse position in window pixels to the display pixel under it.
+// The window may be any multiple of the display resolution.
+fn mouse_to_pixel(
+    mouse: (f32, f32),
+    window_size: (usize, usize),
+    resolution: (usize, usize),
+) -> Option<(usize, usize)> {
+    if mouse.0 < 0.0 || mouse.1 < 0.0 || window_size.0 == 0 || window_size.1 == 0 {
+        return None;
+    }
+    let x = mouse.0 as usize * resolution.0 / window_size.0;
+    let y = mouse.1 as usize * resolution.1 / window_size.1;
+    if x < resolution.0 && y < resolution.1 {
+        Some((x, y))
+    } else {
+        None
+    }
+}
+
+fn inspect_title(window: &Window, emulator: &Emulator) -> String {
+    let pixel = window
+        .get_unscaled_mouse_pos(MouseMode::Discard)
+        .and_then(|mouse| mouse_to_pixel(mouse, window.get_size(), (WIDTH, HEIGHT)));
+    match pixel {
+        Some((x, y)) => {
+            let state = if emulator.pixel(x, y) { "on" } else { "off" };
+            format!("Chip8 - ({}, {}) {}", x, y, state)
+        }
+        None => "Chip8".to_string(),
+    }
+}
+
+fn read_keys(window: &Window, emulator: &mut Emulator, pad: &[Key; 16]) {
+    for (k, key) in pad.iter().enumerate() {
+        emulator.set_key(k as u8, window.is_key_down(*key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts presses of key 5 in V0 and shows the count as a digit in the
+    // top-left corner.
+    const KEY5_COUNTER: [u8; 22] = [
+        0x61, 0x05, // 200: LD V1, 5
+        0x60, 0x00, // 202: LD V0, 0
+        0xe1, 0x9e, // 204: SKP V1
+        0x12, 0x04, // 206: JP 204
+        0xe1, 0xa1, // 208: SKNP V1
+        0x12, 0x08, // 20A: JP 208
+        0x70, 0x01, // 20C: ADD V0, 1
+        0x00, 0xe0, // 20E: CLS
+        0xf0, 0x29, // 210: LD F, V0
+        0xd2, 0x25, // 212: DRW V2, V2, 5
+        0x12, 0x04, // 214: JP 204
+    ];
+
+    fn emulator(rom: &[u8]) -> Emulator {
+        let config = Config {
+            timing: TimingMode::Virtual {
+                cycles_per_tick: 10,
+            },
+            seed: Some(0),
+            ..Config::default()
+        };
+        let mut emulator = Emulator::new(&config);
+        emulator.load_rom(rom).unwrap();
+        emulator
+    }
+
+    // The screen with the font glyph for digit at (0, 0) and nothing else.
+    fn glyph_screen_hash(digit: u8) -> u64 {
+        let mut expected = Emulator::new(&Config::default());
+        let glyph = 5 * digit as usize;
+        let rows: Vec<u8> = expected.memory.mem[glyph..glyph + 5].to_vec();
+        for (y, row) in rows.into_iter().enumerate() {
+            for x in 0..8 {
+                expected.set_pixel(x, y, row & (0x80 >> x) != 0);
+            }
+        }
+        expected.screen_hash()
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("chip8-test-{}", name));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn load_state_resumes_the_saved_state() {
+        let mut emu = emulator(&KEY5_COUNTER);
+        for _ in 0..2 {
+            emu.set_key(5, true);
+            headless::run_frame(&mut emu).unwrap();
+            emu.set_key(5, false);
+            headless::run_frame(&mut emu).unwrap();
+        }
+        emu.memory.stack.push(0x20c).unwrap();
+        let saved = snapshot::save(&emu);
+        let path = temp_path("resume.state");
+        std::fs::write(&path, &saved).unwrap();
+
+        let options = options::parse(&args(&["--load-state", &path])).unwrap();
+        let (resumed, state_path) = start(&options, &options.config).unwrap();
+        assert_eq!(state_path, path);
+        assert_eq!(snapshot::save(&resumed), saved);
+        assert_eq!(resumed.state.v[0], 2);
+        assert_eq!(resumed.screen_hash(), emu.screen_hash());
+        assert_eq!(resumed.memory.stack.entries, vec![0x20c]);
+    }
+
+    #[test]
+    fn snapshots_keep_deep_stacks() {
+        let config = Config {
+            stack_depth: 300,
+            ..Config::default()
+        };
+        let mut emu = Emulator::new(&config);
+        for i in 0..300 {
+            emu.memory.stack.push(0x200 + i).unwrap();
+        }
+        let mut restored = Emulator::new(&config);
+        snapshot::restore(&mut restored, &snapshot::save(&emu)).unwrap();
+        assert_eq!(restored.memory.stack.entries, emu.memory.stack.entries);
+    }
+
+    #[test]
+    fn clipped_sprite_rows_are_skipped() {
+        // LD V0, 0 ; LD V1, 31 ; LD I, 0x300 ; DRW V0, V1, 4
+        let mut emu = emulator(&[0x60, 0x00, 0x61, 0x1f, 0xa3, 0x00, 0xd0, 0x14]);
+        emu.memory.mem[0x300..0x304].copy_from_slice(&[0x00, 0xff, 0xff, 0xff]);
+        // Rows 1-3 would land on these if they wrapped instead of clipping.
+        for x in 0..8 {
+            emu.set_pixel(x, 0, true);
+            emu.set_pixel(x, 1, true);
+        }
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.state.v[0xf], 0);
+        assert!((0..WIDTH).all(|x| !emu.pixel(x, 31)));
+        assert!((0..8).all(|x| emu.pixel(x, 0) && emu.pixel(x, 1)));
+    }
+
+    #[test]
+    fn sprite_reads_past_memory_fail_or_wrap() {
+        // LD I, 0xFFE ; DRW V0, V0, 5
+        let rom = [0xaf, 0xfe, 0xd0, 0x05];
+        let mut emu = emulator(&rom);
+        emu.step().unwrap();
+        assert!(emu.step().is_err());
+
+        let mut emu = emulator(&rom);
+        emu.quirks.memory_wrap = true;
+        emu.memory.mem[0xffe..].copy_from_slice(&[0x80, 0x80]);
+        emu.step().unwrap();
+        emu.step().unwrap();
+        // Rows 2-4 come from the font's 0, which starts 0xf0 0x90 0x90.
+        assert!(emu.pixel(0, 0) && emu.pixel(0, 1) && emu.pixel(0, 2));
+        assert!(emu.pixel(3, 2) && !emu.pixel(1, 3) && emu.pixel(3, 4));
+    }
+
+    #[test]
+    fn fetch_past_memory_fails() {
+        let mut emu = emulator(&KEY5_COUNTER);
+        emu.policy.denied.push("FX75".parse().unwrap());
+        emu.state.ip = 0xfff;
+        assert!(emu.step().is_err());
+    }
+
+    #[test]
+    fn display_wait_ticks_before_the_next_instruction() {
+        // LD V0, 5 ; LD DT, V0 ; DRW V0, V0, 1 ; LD V1, DT
+        let mut emu = emulator(&[0x60, 0x05, 0xf0, 0x15, 0xd0, 0x01, 0xf1, 0x07]);
+        emu.quirks.display_wait = true;
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.step().unwrap(), StepEvent::FrameComplete);
+        assert_eq!(emu.cycles_executed(), 10);
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[1], 4);
+        assert_eq!(emu.emulated_frame(), 1);
+    }
+
+    #[test]
+    fn display_wait_on_a_tick_counts_one_frame() {
+        // LD V0, 5 ; LD DT, V0 ; JP 208 ; (208) DRW V0, V0, 1 ; LD V1, DT
+        let rom = [
+            0x60, 0x05, 0xf0, 0x15, 0x12, 0x08, 0x00, 0x00, 0xd0, 0x01, 0xf1, 0x07,
+        ];
+        let mut emu = emulator(&rom);
+        emu.quirks.display_wait = true;
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        // The draw lands on the 10th cycle, where the tick is due anyway.
+        emu.timer.cycles = 9;
+        assert_eq!(emu.step().unwrap(), StepEvent::FrameComplete);
+        emu.step().unwrap();
+        assert_eq!(emu.emulated_frame(), 1);
+        assert_eq!(emu.state.v[1], 4);
+    }
+
+    // Runs until the debugger wants to stop, for at most limit steps.
+    fn run_to_break(debugger: &mut Debugger, emu: &mut Emulator, limit: usize) {
+        for _ in 0..limit {
+            if debugger.should_break(emu) {
+                return;
+            }
+            emu.step().unwrap();
+        }
+        panic!("no break within {} steps", limit);
+    }
+
+    #[test]
+    fn break_op_stops_at_the_first_draw() {
+        let mut debugger = Debugger::new();
+        let mut emu = emulator(&KEY5_COUNTER);
+        debugger.command(&mut emu, "break-op DRW").unwrap();
+        debugger.command(&mut emu, "continue").unwrap();
+        emu.set_key(5, true);
+        for _ in 0..6 {
+            assert!(!debugger.should_break(&mut emu));
+            emu.step().unwrap();
+        }
+        emu.set_key(5, false);
+        run_to_break(&mut debugger, &mut emu, 100);
+        assert_eq!(emu.state.ip, 0x212);
+        assert_eq!(emu.state.v[0], 1);
+    }
+
+    #[test]
+    fn break_op_takes_one_family() {
+        let mut debugger = Debugger::new();
+        let mut emu = emulator(&[0x60, 0x00, 0xf1, 0x0a]);
+        assert!(debugger.command(&mut emu, "break-op LD").is_err());
+        debugger.command(&mut emu, "break-op fx0a").unwrap();
+        debugger.command(&mut emu, "continue").unwrap();
+        run_to_break(&mut debugger, &mut emu, 10);
+        assert_eq!(emu.state.ip, 0x202);
+    }
+
+    #[test]
+    fn shutdown_autosaves_and_keeps_the_run_error() {
+        let emu = emulator(&KEY5_COUNTER);
+        let path = temp_path("autosave.state");
+        let _ = std::fs::remove_file(&path);
+        let options = options::parse(&args(&["--autosave", &path])).unwrap();
+        let err = shutdown(&options, &emu, Err(anyhow!("run failed"))).unwrap_err();
+        assert_eq!(err.to_string(), "run failed");
+        assert_eq!(std::fs::read(&path).unwrap(), snapshot::save(&emu));
+
+        let missing = temp_path("no-such-dir/autosave.state");
+        let options = options::parse(&args(&["--autosave", &missing])).unwrap();
+        let err = shutdown(&options, &emu, Err(anyhow!("run failed"))).unwrap_err();
+        assert_eq!(err.to_string(), "run failed");
+        assert!(shutdown(&options, &emu, Ok(())).is_err());
+    }
+
+    #[test]
+    fn writes_invalidate_only_the_entries_they_touch() {
+        let mut emu = emulator(&KEY5_COUNTER);
+        for ip in &[0x200, 0x202, 0x204] {
+            emu.memory.decode_at(*ip).unwrap();
+        }
+        emu.memory.write(0x203, 0x07, false).unwrap();
+        let entries = &emu.memory.decode_cache.entries;
+        let cached: Vec<usize> = (0..entries.len())
+            .filter(|&ip| entries[ip].is_some())
+            .collect();
+        assert_eq!(cached, vec![0x200, 0x204]);
+        assert!(matches!(
+            emu.memory.decode_at(0x202),
+            Ok(Instruction::SetImmediate(Register(0), Constant(0x07)))
+        ));
+        // The last whole word in memory caches like any other.
+        emu.memory.write(0xffe, 0x00, false).unwrap();
+        emu.memory.write(0xfff, 0xe0, false).unwrap();
+        assert!(matches!(
+            emu.memory.decode_at(0xffe),
+            Ok(Instruction::ClearScreen)
+        ));
+        assert!(emu.memory.decode_cache.entries[0xffe].is_some());
+    }
+
+    #[test]
+    fn self_modifying_code_runs_the_new_bytes() {
+        let mut emu = emulator(&[
+            0x60, 0x71, // 200: LD V0, 0x71
+            0x61, 0x05, // 202: LD V1, 0x05
+            0xa2, 0x0a, // 204: LD I, 0x20A
+            0xf2, 0x55, // 206: LD [I], V2
+            0x63, 0x00, // 208: LD V3, 0
+            0x00, 0x00, // 20A: becomes ADD V1, 5
+        ]);
+        emu.memory.decode_at(0x20a).unwrap();
+        for _ in 0..6 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.state.v[1], 10);
+    }
+
+    #[test]
+    fn rom_writes_trap_inside_the_image_only() {
+        let config = Config {
+            rom_writes: RomWrites::Trap,
+            ..Config::default()
+        };
+        let mut emu = Emulator::new(&config);
+        emu.load_rom(&KEY5_COUNTER).unwrap();
+        assert!(emu.memory.write(0x200, 0, false).is_err());
+        assert!(emu.memory.write(0x215, 0, false).is_err());
+        assert!(emu.memory.write(0x216, 0, false).is_ok());
+        assert!(emu.memory.write(0xe00, 0, false).is_ok());
+
+        let mut restored = Emulator::new(&config);
+        snapshot::restore(&mut restored, &snapshot::save(&emu)).unwrap();
+        assert!(restored.memory.write(0x20c, 0, false).is_err());
+        assert!(restored.memory.write(0xe00, 0, false).is_ok());
+    }
+
+    // Hands out the same bytes in order, from the start again on reseed.
+    struct Canned {
+        bytes: Vec<u8>,
+        next: usize,
+    }
+
+    impl RandomSource for Canned {
+        fn next_u8(&mut self) -> u8 {
+            let b = self.bytes[self.next % self.bytes.len()];
+            self.next += 1;
+            b
+        }
+
+        fn reseed(&mut self, _seed: u64) {
+            self.next = 0;
+        }
+    }
+
+    #[test]
+    fn store_random_masks_the_injected_bytes() {
+        // RND V0, 0x0F ; RND V1, 0xF0 ; RND V2, 0xFF
+        let mut emu = emulator(&[0xc0, 0x0f, 0xc1, 0xf0, 0xc2, 0xff]);
+        emu.set_random_source(Box::new(Canned {
+            bytes: vec![0xab, 0xcd, 0x5a],
+            next: 0,
+        }));
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(&emu.state.v[..3], &[0x0b, 0xc0, 0x5a]);
+
+        emu.reset(false);
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[0], 0x0b);
+    }
+
+    #[test]
+    fn base_chip8_is_always_reported() {
+        let capabilities = Emulator::capabilities();
+        assert_ne!(capabilities.chip8, Support::No);
+        assert_eq!(
+            capabilities.chip8 == Support::Yes,
+            capabilities.unimplemented.is_empty()
+        );
+        assert_eq!(
+            capabilities.superchip != Support::No,
+            cfg!(feature = "superchip")
+        );
+        assert_eq!(capabilities.xochip != Support::No, cfg!(feature = "xochip"));
+        // Exactly the listed base opcodes fail when they run.
+        for &(pattern, _) in debugger::FAMILIES.iter() {
+            let word = pattern
+                .replace('X', "1")
+                .replace('Y', "2")
+                .replace('N', "0");
+            let word = u16::from_str_radix(&word, 16).unwrap();
+            let mut emu = emulator(&word.to_be_bytes());
+            // Something for 00EE to return to.
+            emu.memory.stack.push(0x200).unwrap();
+            let result = emu.step();
+            let listed = capabilities.unimplemented.contains(&pattern);
+            assert_eq!(result.is_err(), listed, "{} {:?}", pattern, result);
+        }
+    }
+
+    #[test]
+    fn cheats_hold_memory_across_frames() {
+        let mut emu = emulator(&[
+            0x60, 0x42, // 200: LD V0, 0x42
+            0xa3, 0xa0, // 202: LD I, 0x3A0
+            0xf1, 0x55, // 204: LD [I], V1
+            0x70, 0x01, // 206: ADD V0, 1
+            0x12, 0x02, // 208: JP 202
+        ]);
+        let mut debugger = Debugger::new();
+        debugger.command(&mut emu, "cheat 0x3a0:ff").unwrap();
+        assert_eq!(emu.cheats.get(&0x3a0), Some(&0xff));
+        debugger.command(&mut emu, "cheat 3a0:10").unwrap();
+        assert_eq!(emu.cheats.get(&0x3a0), Some(&0x10));
+        assert!(debugger.command(&mut emu, "cheat 3a0:100").is_err());
+        assert!(debugger.command(&mut emu, "cheat 1000:01").is_err());
+        for _ in 0..5 {
+            headless::run_frame(&mut emu).unwrap();
+            assert_eq!(emu.memory.mem[0x3a0], 0x10);
+        }
+        debugger.command(&mut emu, "uncheat 3A0").unwrap();
+        headless::run_frame(&mut emu).unwrap();
+        assert_ne!(emu.memory.mem[0x3a0], 0x10);
+    }
+
+    #[test]
+    fn analyze_spots_a_byte_swapped_rom() {
+        let rom = include_bytes!("../roms/PONG").to_vec();
+        assert!(!disasm::looks_swapped(&rom));
+        let mut swapped = rom;
+        swapped.chunks_exact_mut(2).for_each(|word| word.swap(0, 1));
+        assert!(disasm::looks_swapped(&swapped));
+        assert!(disasm::analyze(&swapped).contains("swap each pair of bytes"));
+    }
+
+    #[test]
+    fn oversized_files_analyze_and_disassemble() {
+        // Jumps and skips all the way through, past where addresses fit.
+        let rom: Vec<u8> = [0x12, 0x00, 0x30, 0x00].repeat(0x4800);
+        assert!(disasm::analyze(&rom).contains("decodes as-is"));
+        assert!(disasm::disassemble(&rom).contains("121FE: 3000  SE V0, 0x00\n"));
+    }
+
+    #[test]
+    fn disassembly_labels_jump_targets() {
+        // 200: JP 204 ; 202: JP 205 ; 204: CLS
+        let listing = disasm::disassemble(&[0x12, 0x04, 0x12, 0x05, 0x00, 0xe0]);
+        assert!(listing.contains("200: 1204  JP 0x204"), "{}", listing);
+        assert!(listing.contains("; L_204\n"), "{}", listing);
+        assert!(listing.contains("L_204:\n    204: 00E0"), "{}", listing);
+        // No line starts at an odd address, so there is nothing to label.
+        assert!(listing.contains("; 205 (off-grid)\n"), "{}", listing);
+        assert!(!listing.contains("L_205"), "{}", listing);
+    }
+
+    #[test]
+    fn virtual_ticks_land_on_every_nth_instruction() {
+        // LD V0, 5 ; LD DT, V0 ; JP 204
+        let config = Config {
+            timing: TimingMode::Virtual { cycles_per_tick: 4 },
+            ..Config::default()
+        };
+        let mut emu = Emulator::new(&config);
+        emu.load_rom(&[0x60, 0x05, 0xf0, 0x15, 0x12, 0x04]).unwrap();
+        let mut timers = vec![];
+        for _ in 0..8 {
+            emu.step().unwrap();
+            timers.push(emu.delay_timer());
+        }
+        assert_eq!(timers, vec![0, 5, 5, 4, 4, 4, 4, 3]);
+        assert_eq!(emu.emulated_frame(), 2);
+    }
+
+    #[test]
+    fn debugger_pokes_reach_later_instructions() {
+        let mut emu = emulator(&[
+            0x73, 0x01, // 200: ADD V3, 1
+            0xf3, 0x33, // 202: LD B, V3
+            0xf1, 0x07, // 204: LD V1, DT
+            0x12, 0x06, // 206: JP 206, poked into LD V2, 0x42
+            0x12, 0x08, // 208: JP 208
+        ]);
+        let mut debugger = Debugger::new();
+        assert!(debugger.should_break(&mut emu));
+        for command in &[
+            "set reg V3 0x10",
+            "set i 0x300",
+            "set dt 9",
+            "set mem 0x206 0x62",
+            "set mem 0x207 0x42",
+            "set pixel 3 4 on",
+        ] {
+            let action = debugger.command(&mut emu, command).unwrap();
+            assert_eq!(action, debugger::Action::Prompt);
+        }
+        for command in &[
+            "set reg V16 1",
+            "set reg V3 0x100",
+            "set i 0x1000",
+            "set mem 0x1000 1",
+            "set mem 0x300 256",
+            "set pixel 64 4 on",
+            "set pixel 3 32 on",
+            "set pixel 3 4 maybe",
+        ] {
+            assert!(debugger.command(&mut emu, command).is_err(), "{}", command);
+        }
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.state.v[3], 0x11);
+        assert_eq!(&emu.memory.mem[0x300..0x303], &[0, 1, 7]);
+        assert_eq!(emu.state.v[1], 9);
+        assert_eq!(emu.state.v[2], 0x42);
+        assert_eq!(emu.state.ip, 0x208);
+        assert!(emu.pixel(3, 4));
+    }
+
+    #[test]
+    fn getkey_delivers_on_press_or_release() {
+        for &on_release in &[true, false] {
+            // LD V1, K ; JP 202
+            let mut emu = emulator(&[0xf1, 0x0a, 0x12, 0x02]);
+            emu.quirks.getkey_on_release = on_release;
+            emu.step().unwrap();
+            assert_eq!(emu.state.ip, 0x200);
+            emu.set_key(5, true);
+            for _ in 0..3 {
+                emu.step().unwrap();
+            }
+            // Held, the key only gets through when delivery is on press.
+            assert_eq!(emu.state.ip == 0x202, !on_release);
+            emu.set_key(5, false);
+            emu.step().unwrap();
+            assert_eq!(emu.state.ip, 0x202);
+            assert_eq!(emu.state.v[1], 5);
+        }
+    }
+
+    #[test]
+    fn one_frame_of_steps_completes_one_frame() {
+        let config = Config {
+            timing: TimingMode::Frame {
+                cycles_per_frame: 7,
+            },
+            ..Config::default()
+        };
+        let mut emu = Emulator::new(&config);
+        // ADD V0, 1 ; JP 200
+        emu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let events: Vec<StepEvent> = (0..7).map(|_| emu.step().unwrap()).collect();
+        assert_eq!(
+            events
+                .iter()
+                .filter(|&&e| e == StepEvent::FrameComplete)
+                .count(),
+            1
+        );
+        assert_eq!(events[6], StepEvent::FrameComplete);
+        assert_eq!(emu.cycles_remaining_in_frame(), Some(7));
+    }
+
+    #[test]
+    fn check_rom_flags_files_that_are_not_programs() {
+        assert_eq!(check_rom(include_bytes!("../roms/MAZE")), None);
+        assert_eq!(check_rom(&KEY5_COUNTER), None);
+        assert!(check_rom(&[0; 64]).is_some());
+        assert!(check_rom(&[0xff; 64]).is_some());
+        assert!(check_rom(&[0x12]).is_some());
+    }
+
+    #[test]
+    fn config_summary_shows_every_quirk() {
+        let defaults = Config::default().to_string();
+        for line in &[
+            "  getkey_on_release: true\n",
+            "  shift: VY into VX\n",
+            "  display_wait: false\n",
+            "  memory_wrap: false\n",
+            "  key_debounce: false\n",
+        ] {
+            assert!(defaults.contains(line), "{}", defaults);
+        }
+        let options = options::parse(&args(&[
+            "--getkey-on-press",
+            "--shift-in-place",
+            "--display-wait",
+            "--wrap-memory",
+            "--debounce-keys",
+        ]))
+        .unwrap();
+        let changed = options.config.to_string();
+        for line in &[
+            "  getkey_on_release: false\n",
+            "  shift: VX in place\n",
+            "  display_wait: true\n",
+            "  memory_wrap: true\n",
+            "  key_debounce: true\n",
+        ] {
+            assert!(changed.contains(line), "{}", changed);
+        }
+    }
+
+    #[test]
+    fn presenter_shows_the_first_draw_of_each_frame() {
+        use StepEvent::*;
+        let events = [Executed, Drew, Drew, Executed, FrameComplete, Drew, Waiting];
+        let mut presenter = Presenter {
+            present_on_draw: true,
+            ..Presenter::default()
+        };
+        let presented: Vec<bool> = events
+            .iter()
+            .map(|&e| presenter.should_present(e))
+            .collect();
+        assert_eq!(presented, [false, true, false, false, true, true, false]);
+
+        let mut presenter = Presenter::default();
+        let presented: Vec<bool> = events
+            .iter()
+            .map(|&e| presenter.should_present(e))
+            .collect();
+        assert_eq!(presented, [false, false, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn timer_setters_are_seen_by_the_program() {
+        // LD V2, DT ; JP 202
+        let mut emu = emulator(&[0xf2, 0x07, 0x12, 0x02]);
+        emu.set_delay_timer(0x42);
+        emu.set_sound_timer(3);
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[2], 0x42);
+        assert_eq!((emu.delay_timer(), emu.sound_timer()), (0x42, 3));
+    }
+
+    #[test]
+    fn next_steps_over_a_call() {
+        let mut emu = emulator(&[
+            0x22, 0x06, // 200: CALL 206
+            0x71, 0x01, // 202: ADD V1, 1
+            0x12, 0x04, // 204: JP 204
+            0x72, 0x01, // 206: ADD V2, 1
+            0x72, 0x01, // 208: ADD V2, 1
+            0x00, 0xee, // 20A: RET
+        ]);
+        let mut debugger = Debugger::new();
+        assert!(debugger.should_break(&mut emu));
+        let action = debugger.command(&mut emu, "next").unwrap();
+        assert_eq!(action, debugger::Action::Continue);
+        emu.step().unwrap();
+        run_to_break(&mut debugger, &mut emu, 10);
+        assert_eq!(emu.state.ip, 0x202);
+        assert_eq!(emu.state.v[2], 2);
+        assert!(emu.memory.stack.entries.is_empty());
+        // Anything but a call is a single step.
+        let action = debugger.command(&mut emu, "next").unwrap();
+        assert_eq!(action, debugger::Action::Step);
+    }
+
+    #[test]
+    fn add_immediate_wraps_without_touching_vf() {
+        // ADD V0, 1 ; ADD V0, 1
+        let mut emu = emulator(&[0x70, 0x01, 0x70, 0x01]);
+        emu.state.v[0] = 0xff;
+        emu.state.v[0xf] = 0x55;
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[0], 0);
+        assert_eq!(emu.state.v[0xf], 0x55);
+        assert!(emu.last_add_overflowed());
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[0], 1);
+        assert!(!emu.last_add_overflowed());
+    }
+
+    #[test]
+    fn calls_overflow_one_past_the_stack_depth() {
+        for &depth in &[1, 2, 16] {
+            let config = Config {
+                stack_depth: depth,
+                ..Config::default()
+            };
+            let mut emu = Emulator::new(&config);
+            // CALL 200, forever
+            emu.load_rom(&[0x22, 0x00]).unwrap();
+            for _ in 0..depth {
+                emu.step().unwrap();
+            }
+            assert_eq!(emu.memory.stack.entries.len(), depth);
+            let err = emu.step().unwrap_err();
+            assert!(err.to_string().contains("overflow"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn mouse_positions_map_to_display_pixels() {
+        let cases = [
+            ((0.0, 0.0), (512, 256), (64, 32), Some((0, 0))),
+            ((7.9, 8.0), (512, 256), (64, 32), Some((0, 1))),
+            ((511.0, 255.0), (512, 256), (64, 32), Some((63, 31))),
+            ((512.0, 0.0), (512, 256), (64, 32), None),
+            ((-1.0, 4.0), (512, 256), (64, 32), None),
+            ((100.0, 50.0), (640, 320), (64, 32), Some((10, 5))),
+            ((12.0, 4.0), (512, 256), (128, 64), Some((3, 1))),
+            ((3.0, 3.0), (0, 0), (64, 32), None),
+        ];
+        for &(mouse, window, resolution, pixel) in &cases {
+            assert_eq!(
+                mouse_to_pixel(mouse, window, resolution),
+                pixel,
+                "{:?}",
+                mouse
+            );
+        }
+    }
+
+    #[test]
+    fn roms_diverge_at_the_first_differing_frame() {
+        let rom = [
+            0x60, 0x03, // 200: LD V0, 3
+            0xf0, 0x15, // 202: LD DT, V0
+            0xf1, 0x07, // 204: LD V1, DT
+            0x31, 0x00, // 206: SE V1, 0
+            0x12, 0x04, // 208: JP 204
+            0xd0, 0x01, // 20A: DRW V0, V0, 1
+            0x12, 0x0c, // 20C: JP 20C
+        ];
+        let mut changed = rom;
+        // 20A: LD V0, 1, so nothing is ever drawn.
+        changed[10..12].copy_from_slice(&[0x60, 0x01]);
+        let divergence =
+            headless::first_divergence(&mut emulator(&rom), &mut emulator(&changed), 10);
+        assert_eq!(divergence.unwrap(), Some(4));
+        let same = headless::first_divergence(&mut emulator(&rom), &mut emulator(&rom), 10);
+        assert_eq!(same.unwrap(), None);
+    }
+
+    #[test]
+    fn emulated_frames_count_ticks() {
+        // ADD V0, 1 ; JP 200
+        let mut emu = emulator(&[0x70, 0x01, 0x12, 0x00]);
+        for _ in 0..20 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.cycles_executed(), 20);
+        assert_eq!(emu.emulated_frame(), 2);
+        // A tick brought forward counts as a frame.
+        emu.tick_timers_once();
+        assert_eq!(emu.emulated_frame(), 3);
+    }
+
+    #[test]
+    fn shifts_leave_the_shifted_out_bit_in_vf() {
+        // (opcode, input, result, VF) for SHR/SHL V0 with V1 as the source.
+        let cases = [
+            (0x8016, 0x01, 0x00, 1),
+            (0x801e, 0x80, 0x00, 1),
+            (0x8016, 0x02, 0x01, 0),
+            (0x801e, 0x40, 0x80, 0),
+        ];
+        for &shift in &[Behavior::Old, Behavior::New] {
+            for &(opcode, input, result, flag) in &cases {
+                let [hi, lo] = u16::to_be_bytes(opcode);
+                let mut emu = emulator(&[hi, lo]);
+                emu.quirks.shift = shift;
+                // The same input either way, whichever register is shifted.
+                emu.state.v[0] = input;
+                emu.state.v[1] = input;
+                emu.step().unwrap();
+                assert_eq!((emu.state.v[0], emu.state.v[0xf]), (result, flag));
+
+                // Shifting VF itself leaves the flag, not the result, in VF.
+                let mut emu = emulator(&[hi | 0x0f, lo | 0xf0]);
+                emu.quirks.shift = shift;
+                emu.state.v[0xf] = input;
+                emu.step().unwrap();
+                assert_eq!(emu.state.v[0xf], flag, "{:04x} {:?}", opcode, shift);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_restore_into_shorter_frames() {
+        let slow = Config {
+            timing: TimingMode::Virtual {
+                cycles_per_tick: 100,
+            },
+            ..Config::default()
+        };
+        let mut emu = Emulator::new(&slow);
+        // ADD V0, 1 ; JP 200
+        emu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        for _ in 0..50 {
+            emu.step().unwrap();
+        }
+        let mut restored = emulator(&[]);
+        snapshot::restore(&mut restored, &snapshot::save(&emu)).unwrap();
+        assert_eq!(restored.cycles_remaining_in_frame(), Some(1));
+        assert_eq!(restored.step().unwrap(), StepEvent::FrameComplete);
+        assert_eq!(restored.emulated_frame(), 1);
+    }
+
+    #[test]
+    fn draws_from_low_memory_warn_once_per_site() {
+        assert!(!reads_low_memory(0x000, 5));
+        assert!(!reads_low_memory(FONT_SIZE as u16 - 5, 5));
+        assert!(reads_low_memory(FONT_SIZE as u16 - 4, 5));
+        assert!(reads_low_memory(0x100, 5));
+        assert!(!reads_low_memory(0x200, 15));
+
+        let mut emu = emulator(&[
+            0x60, 0x07, // 200: LD V0, 7
+            0xf0, 0x29, // 202: LD F, V0
+            0xd0, 0x05, // 204: DRW V0, V0, 5
+            0xa1, 0x00, // 206: LD I, 0x100
+            0xd0, 0x05, // 208: DRW V0, V0, 5
+            0x12, 0x08, // 20A: JP 208
+        ]);
+        emu.warn_draw_low = true;
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert!(emu.warned_draws.is_empty());
+        for _ in 0..6 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.warned_draws.iter().collect::<Vec<_>>(), [&0x208]);
+    }
+
+    #[test]
+    fn display_grid_holds_a_drawn_glyph() {
+        let mut emu = emulator(&[
+            0x60, 0x01, // 200: LD V0, 1
+            0xf0, 0x29, // 202: LD F, V0
+            0x61, 0x02, // 204: LD V1, 2
+            0x62, 0x03, // 206: LD V2, 3
+            0xd1, 0x25, // 208: DRW V1, V2, 5
+        ]);
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        let grid = emu.display_grid();
+        assert_eq!((grid.len(), grid[0].len()), (HEIGHT, WIDTH));
+        // The font's 1 is 20 60 20 20 70, drawn from (2, 3).
+        let expected = vec![
+            (4, 3),
+            (3, 4),
+            (4, 4),
+            (4, 5),
+            (4, 6),
+            (3, 7),
+            (4, 7),
+            (5, 7),
+        ];
+        let mut lit: Vec<(usize, usize)> = vec![];
+        for (y, row) in grid.iter().enumerate() {
+            lit.extend((0..WIDTH).filter(|&x| row[x]).map(|x| (x, y)));
+        }
+        assert_eq!(lit, expected);
+    }
+
+    #[test]
+    fn observers_see_each_instruction_once() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct DrawCounter(Rc<Cell<u32>>);
+
+        impl ExecObserver for DrawCounter {
+            fn after(&mut self, _ip: u16, instruction: &Instruction, _emu: &Emulator) {
+                if let Instruction::DrawSprite(..) = instruction {
+                    self.0.set(self.0.get() + 1);
+                }
+            }
+        }
+
+        // DRW V0, V0, 1 ; ADD V0, 1 ; JP 200
+        let mut emu = emulator(&[0xd0, 0x01, 0x70, 0x01, 0x12, 0x00]);
+        let draws = Rc::new(Cell::new(0));
+        emu.set_observer(Some(Box::new(DrawCounter(draws.clone()))));
+        for _ in 0..9 {
+            emu.step().unwrap();
+        }
+        assert_eq!(draws.get(), 3);
+    }
+
+    #[test]
+    fn register_dumps_after_a_short_run() {
+        let mut emu = emulator(&[
+            0x60, 0x2a, // 200: LD V0, 0x2A
+            0x6f, 0x01, // 202: LD VF, 1
+            0xa3, 0x00, // 204: LD I, 0x300
+            0xf0, 0x15, // 206: LD DT, V0
+            0x22, 0x0c, // 208: CALL 20C
+            0x00, 0x00, // 20A
+            0x12, 0x0c, // 20C: JP 20C
+        ]);
+        for _ in 0..6 {
+            emu.step().unwrap();
+        }
+        let regs = headless::dump(&emu, headless::Dump::Registers);
+        let mut expected = String::from("ip=20c\ni=300\nsp=1\ndt=2a\nst=00\nv0=2a\n");
+        for i in 1..0xf {
+            expected.push_str(&format!("v{:x}=00\n", i));
+        }
+        expected.push_str("vf=01\n");
+        assert_eq!(regs, expected);
+        let mem = headless::dump(
+            &emu,
+            headless::Dump::Memory {
+                start: 0x200,
+                len: 2,
+            },
+        );
+        assert_eq!(mem, "mem:200=60 2a\n");
+    }
+
+    #[test]
+    fn screen_hash_tells_screens_apart() {
+        let mut a = emulator(&KEY5_COUNTER);
+        let mut b = emulator(&KEY5_COUNTER);
+        assert_eq!(a.screen_hash(), b.screen_hash());
+        a.set_pixel(3, 4, true);
+        b.set_pixel(3, 4, true);
+        assert_eq!(a.screen_hash(), b.screen_hash());
+        b.set_pixel(63, 31, true);
+        assert_ne!(a.screen_hash(), b.screen_hash());
+        b.set_pixel(63, 31, false);
+        assert_eq!(a.screen_hash(), b.screen_hash());
+    }
+
+    #[test]
+    fn legacy_timing_runs_one_instruction_between_presents() {
+        let options = options::parse(&args(&["--timing", "legacy"])).unwrap();
+        assert_eq!(options.config.timing, TimingMode::Legacy);
+        let mut emu = Emulator::new(&options.config);
+        // ADD V0, 1 ; JP 200
+        emu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        emu.timer.last_update = Instant::now();
+        let mut presenter = Presenter::default();
+        for cycles in 1..=20 {
+            let event = emu.step().unwrap();
+            assert_eq!(event, StepEvent::Executed);
+            assert!(!presenter.should_present(event));
+            assert_eq!(emu.cycles_executed(), cycles);
+        }
+        std::thread::sleep(Duration::from_millis(17));
+        let event = emu.step().unwrap();
+        assert!(presenter.should_present(event));
+        assert_eq!(emu.cycles_executed(), 21);
+        assert_eq!(emu.emulated_frame(), 1);
+    }
+
+    #[test]
+    fn modified_bindings_need_the_modifier_held() {
+        let keymap = keymap::parse("save-state = LeftCtrl+S\nreset = F2\n0 = 1\n").unwrap();
+        assert_eq!(keymap.pad[0], Key::Key1);
+        let save = keymap.controls.save_state;
+        let s_pressed = |key| key == Key::S;
+        assert!(!save.fired(s_pressed, |_| false));
+        assert!(!save.fired(s_pressed, |key| key == Key::LeftShift));
+        assert!(save.fired(s_pressed, |key| key == Key::LeftCtrl));
+        // Holding the modifier alone is not a press.
+        assert!(!save.fired(|_| false, |key| key == Key::LeftCtrl));
+        let reset = keymap.controls.reset;
+        assert!(reset.fired(|key| key == Key::F2, |_| false));
+        assert!(keymap::parse("reset = Nope+F2\n").is_err());
+    }
+
+    #[test]
+    fn off_alpha_only_changes_off_pixels() {
+        let screen = [true, false];
+        let mut buffer = [0; 2];
+        to_argb(&screen, &mut buffer, OffAlpha::Opaque);
+        assert_eq!((buffer[0] >> 24, buffer[1] >> 24), (0xff, 0xff));
+        to_argb(&screen, &mut buffer, OffAlpha::Transparent);
+        assert_eq!((buffer[0] >> 24, buffer[1] >> 24), (0xff, 0x00));
+        // The colour channels are the same either way.
+        assert_eq!(buffer[0], ON_COLOR);
+        assert_eq!(buffer[1] & 0xffffff, OFF_COLOR & 0xffffff);
+    }
+
+    #[test]
+    fn reset_starts_the_program_over() {
+        let rom = [
+            0xc0, 0xff, // 200: RND V0, 0xFF
+            0xc1, 0xff, // 202: RND V1, 0xFF
+            0xf0, 0x15, // 204: LD DT, V0
+            0xf0, 0x18, // 206: LD ST, V0
+            0xf1, 0x29, // 208: LD F, V1
+            0xd0, 0x05, // 20A: DRW V0, V0, 5
+            0x22, 0x0e, // 20C: CALL 20E
+            0x12, 0x0e, // 20E: JP 20E
+        ];
+        let mut emu = emulator(&rom);
+        let run = |emu: &mut Emulator| {
+            for _ in 0..9 {
+                emu.step().unwrap();
+            }
+            (emu.state.v[0], emu.state.v[1])
+        };
+        let first = run(&mut emu);
+        // Make sure there is something for reset to clear.
+        emu.state.v[0] = first.0 | 1;
+        emu.memory.mem[0x300] = 9;
+        emu.set_delay_timer(1);
+        emu.set_sound_timer(1);
+        assert!(emu.screen.iter().any(|&p| p));
+        assert_eq!(emu.memory.stack.len(), 1);
+
+        emu.reset(true);
+        assert_eq!(emu.state.ip, 0x200);
+        assert_eq!(emu.state.address.0, 0);
+        assert!(emu.state.v.iter().all(|&v| v == 0));
+        assert!(emu.memory.stack.entries.is_empty());
+        assert_eq!((emu.delay_timer(), emu.sound_timer()), (0, 0));
+        assert!(emu.screen.iter().all(|&p| !p));
+        assert_eq!(emu.memory.mem[0x300], 0);
+        assert_eq!(&emu.memory.mem[0x200..0x200 + rom.len()], &rom);
+        assert_eq!((emu.cycles_executed(), emu.emulated_frame()), (0, 0));
+        assert_eq!(run(&mut emu), first);
+    }
+
+    #[test]
+    fn bcd_writes_all_three_digits_or_none() {
+        // LD V0, 234 ; LD I, 0x300 ; LD B, V0
+        let mut emu = emulator(&[0x60, 0xea, 0xa3, 0x00, 0xf0, 0x33]);
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(&emu.memory.mem[0x300..0x303], &[2, 3, 4]);
+
+        // LD V0, 234 ; LD I, 0xFFE ; LD B, V0
+        let rom = [0x60, 0xea, 0xaf, 0xfe, 0xf0, 0x33];
+        let mut emu = emulator(&rom);
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert!(emu.step().is_err());
+        assert_eq!(&emu.memory.mem[0xffe..], &[0, 0]);
+
+        let mut emu = emulator(&rom);
+        emu.quirks.memory_wrap = true;
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(&emu.memory.mem[0xffe..], &[2, 3]);
+        assert_eq!(emu.memory.mem[0], 4);
+    }
+
+    #[test]
+    fn suggested_timing_follows_what_the_rom_does() {
+        let mut drawing = emulator(&[
+            0x00, 0xe0, // 200: CLS
+            0xd0, 0x05, // 202: DRW V0, V0, 5
+            0x60, 0x01, // 204: LD V0, 1
+            0xf0, 0x15, // 206: LD DT, V0
+            0xf1, 0x07, // 208: LD V1, DT
+            0x31, 0x00, // 20A: SE V1, 0
+            0x12, 0x08, // 20C: JP 208
+            0x12, 0x00, // 20E: JP 200
+        ]);
+        // ADD V0, 1 ; JP 200
+        let mut computing = emulator(&[0x70, 0x01, 0x12, 0x00]);
+        let drawing = headless::profile(&mut drawing, 60).unwrap();
+        let computing = headless::profile(&mut computing, 60).unwrap();
+        assert_eq!((drawing.frames, computing.frames), (60, 60));
+        assert!(drawing.draws >= 30, "{:?}", drawing);
+        assert_eq!(computing.draws, 0);
+        assert_eq!(
+            headless::suggest(&drawing),
+            "--timing frame:15 --display-wait"
+        );
+        assert_eq!(headless::suggest(&computing), "--timing frame:30");
+    }
+
+    #[test]
+    fn control_flow_is_classified_by_kind() {
+        let mut state = State {
+            ip: 0x300,
+            ..State::default()
+        };
+        state.v[0] = 0x10;
+        let cases = [
+            (Instruction::Jump(Address(0x210)), Some(0x210)),
+            (Instruction::JumpOffset(Address(0x210)), Some(0x220)),
+            (Instruction::Call(Address(0x400)), Some(0x400)),
+            (
+                Instruction::SkipIfEqual(Register(1), Constant(2)),
+                Some(0x304),
+            ),
+            (
+                Instruction::SkipIfRegistersNotEqual(Register(1), Register(2)),
+                Some(0x304),
+            ),
+            (Instruction::SkipIfNotPressed(Register(1)), Some(0x304)),
+            (Instruction::Return, None),
+        ];
+        for (instruction, target) in &cases {
+            assert!(instruction.is_control_flow(), "{}", instruction);
+            assert_eq!(instruction.jump_target(&state).map(|a| a.0), *target);
+        }
+        for instruction in &[
+            Instruction::ClearScreen,
+            Instruction::AddImmediate(Register(0), Constant(1)),
+            Instruction::DrawSprite(Register(0), Register(1), Constant(5)),
+            Instruction::WaitKeyPress(Register(0)),
+        ] {
+            assert!(!instruction.is_control_flow(), "{}", instruction);
+            assert_eq!(instruction.jump_target(&state).map(|a| a.0), None);
+        }
+    }
+
+    #[test]
+    fn blocked_getkey_resumes_on_the_frame_the_key_arrives() {
+        // LD V1, K ; LD V2, 7 ; JP 204
+        let mut emu = emulator(&[0xf1, 0x0a, 0x62, 0x07, 0x12, 0x04]);
+        emu.quirks.getkey_on_release = false;
+        emu.step().unwrap();
+        for frame in 1..=3 {
+            assert!(emu.is_blocked());
+            assert_eq!(emu.await_tick(), StepEvent::FrameComplete);
+            assert_eq!(emu.emulated_frame(), frame);
+            assert_eq!(emu.cycles_executed(), 10 * frame);
+        }
+        emu.set_key(5, true);
+        emu.step().unwrap();
+        assert!(!emu.is_blocked());
+        assert_eq!((emu.state.ip, emu.state.v[1]), (0x202, 5));
+        assert_eq!(emu.emulated_frame(), 3);
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[2], 7);
+    }
+
+    #[test]
+    fn fingerprints_repeat_for_the_same_rom_and_seed() {
+        let rom = [
+            0x60, 0x05, // 200: LD V0, 5
+            0xc1, 0xff, // 202: RND V1, 0xFF
+            0xf1, 0x29, // 204: LD F, V1
+            0xd0, 0x05, // 206: DRW V0, V0, 5
+            0x12, 0x02, // 208: JP 202
+        ];
+        let fingerprint = |rom: &[u8]| {
+            let mut emu = emulator(rom);
+            for _ in 0..5 {
+                headless::run_frame(&mut emu).unwrap();
+            }
+            headless::fingerprint(&emu)
+        };
+        assert_eq!(fingerprint(&rom), fingerprint(&rom));
+        let mut changed = rom;
+        changed[1] = 0x06;
+        assert_ne!(fingerprint(&rom), fingerprint(&changed));
+    }
+
+    #[test]
+    fn debounced_keys_report_once_per_press() {
+        let mut keypad = Keypad::default();
+        keypad.set(3, true);
+        let polls: Vec<bool> = (0..3).map(|_| keypad.poll_pressed(3, true)).collect();
+        assert_eq!(polls, [true, false, false]);
+        assert!(keypad.is_down(3));
+        keypad.set(3, false);
+        assert!(!keypad.poll_pressed(3, true));
+        keypad.set(3, true);
+        assert!(keypad.poll_pressed(3, true));
+        assert!(!keypad.poll_pressed(3, true));
+        // Without debounce a held key is seen on every check.
+        assert!(keypad.poll_pressed(3, false) && keypad.poll_pressed(3, false));
+
+        // SKP V0 ; JP 200 ; ADD V1, 1 ; JP 200
+        let mut emu = emulator(&[0xe0, 0x9e, 0x12, 0x00, 0x71, 0x01, 0x12, 0x00]);
+        emu.quirks.key_debounce = true;
+        for press in 1..=2 {
+            emu.set_key(0, true);
+            for _ in 0..20 {
+                emu.step().unwrap();
+            }
+            emu.set_key(0, false);
+            emu.step().unwrap();
+            assert_eq!(emu.state.v[1], press);
+        }
+    }
+
+    #[test]
+    fn builtins_load_at_0x200() {
+        let rom = builtins::find("MAZE").unwrap();
+        assert_eq!(rom, &include_bytes!("../roms/MAZE")[..]);
+        let mut emu = emulator(rom);
+        assert_eq!(&emu.memory.mem[0x200..0x200 + rom.len()], rom);
+        emu.step().unwrap();
+        assert_eq!(emu.state.ip, 0x202);
+        assert!(builtins::find("no-such-rom").is_none());
+        assert!(builtins::names()
+            .iter()
+            .all(|&n| builtins::find(n).is_some()));
+    }
+
+    #[test]
+    fn sound_indicator_shows_while_the_timer_runs() {
+        assert!(!sound_indicator_visible(0));
+        assert!(sound_indicator_visible(1));
+        assert!(sound_indicator_visible(0xff));
+
+        // LD V0, 2 ; LD ST, V0 ; JP 204
+        let mut emu = emulator(&[0x60, 0x02, 0xf0, 0x18, 0x12, 0x04]);
+        emu.step().unwrap();
+        assert!(!sound_indicator_visible(emu.sound_timer()));
+        let mut visible = vec![];
+        for _ in 0..3 {
+            headless::run_frame(&mut emu).unwrap();
+            visible.push(sound_indicator_visible(emu.sound_timer()));
+        }
+        assert_eq!(visible, [true, false, false]);
+
+        let mut buffer = vec![OFF_COLOR; WIDTH * HEIGHT];
+        draw_sound_indicator(&mut buffer);
+        assert_eq!(buffer[WIDTH * HEIGHT - 1], SOUND_COLOR);
+        assert_eq!(buffer[0], OFF_COLOR);
+    }
+
+    #[test]
+    fn denied_opcodes_stop_or_are_skipped() {
+        // LD V0, 1 ; LD R, V0 ; LD V1, 2
+        let rom = [0x60, 0x01, 0xf0, 0x75, 0x61, 0x02];
+        let options = options::parse(&args(&["--deny", "FX75"])).unwrap();
+        let mut emu = Emulator::new(&options.config);
+        emu.load_rom(&rom).unwrap();
+        emu.step().unwrap();
+        let err = emu.step().unwrap_err();
+        assert!(err.to_string().contains("denied"), "{}", err);
+        assert_eq!(emu.state.ip, 0x202);
+
+        let options = options::parse(&args(&["--deny", "fx75", "--denied", "skip"])).unwrap();
+        let mut emu = Emulator::new(&options.config);
+        emu.load_rom(&rom).unwrap();
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(
+            (emu.state.ip, emu.state.v[0], emu.state.v[1]),
+            (0x206, 1, 2)
+        );
+        assert_eq!(emu.cycles_executed(), 3);
+        assert!(options::parse(&args(&["--deny", "FZ75"])).is_err());
+    }
+
+    #[test]
+    fn drw_sets_vf_only_on_collision() {
+        let mut emu = emulator(&[
+            0xd0, 0x05, // 200: DRW V0, V0, 5, onto a blank screen
+            0xd0, 0x05, // 202: DRW V0, V0, 5, over itself
+            0xd0, 0x05, // 204: DRW V0, V0, 5, onto a blank screen again
+            0xd0, 0x05, // 206: DRW V0, V0, 5, over itself
+            0x61, 0x0a, // 208: LD V1, 10
+            0xd1, 0x05, // 20A: DRW V1, V0, 5, clear of the first
+        ]);
+        let mut flags = vec![];
+        for _ in 0..6 {
+            emu.step().unwrap();
+            flags.push(emu.state.v[0xf]);
+        }
+        assert_eq!(flags, [0, 1, 0, 1, 1, 0]);
+        assert!(emu.pixel(10, 0) && !emu.pixel(0, 0));
+    }
+
+    #[test]
+    fn sound_flash_leaves_the_display_alone() {
+        let mut emu = emulator(&KEY5_COUNTER);
+        emu.set_pixel(1, 1, true);
+        let before = emu.screen.clone();
+        let mut buffer = vec![0; WIDTH * HEIGHT];
+        to_argb(&emu.screen, &mut buffer, OffAlpha::Opaque);
+        apply_sound_flash(&mut buffer, "border".parse().unwrap());
+        // Only the border is inverted.
+        assert_eq!(buffer[0], ON_COLOR);
+        assert_eq!(buffer[WIDTH + 1], ON_COLOR);
+        assert_eq!(buffer[WIDTH + 2], OFF_COLOR);
+        apply_sound_flash(&mut buffer, "screen:50".parse().unwrap());
+        assert_eq!(buffer[WIDTH + 2] & 0xff, 0x7f);
+        assert_eq!(emu.screen, before);
+        assert!("screen:0".parse::<SoundFlash>().is_err());
+    }
+
+    #[test]
+    fn remaining_base_opcodes_run() {
+        let mut emu = emulator(&[
+            0x80, 0x11, // 200: OR V0, V1
+            0x82, 0x13, // 202: XOR V2, V1
+            0x83, 0x17, // 204: SUBN V3, V1
+            0x84, 0x17, // 206: SUBN V4, V1
+            0x90, 0x10, // 208: SNE V0, V1
+            0x00, 0x00, // 20A: skipped
+            0x91, 0x10, // 20C: SNE V1, V1
+            0xb2, 0x00, // 20E: JP V0, 0x200
+        ]);
+        emu.state.v[..5].copy_from_slice(&[0x0c, 0x0a, 0x0f, 0x03, 0x0b]);
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[0], 0x0e);
+        emu.step().unwrap();
+        assert_eq!(emu.state.v[2], 0x05);
+        emu.step().unwrap();
+        assert_eq!((emu.state.v[3], emu.state.v[0xf]), (0x07, 1));
+        emu.step().unwrap();
+        assert_eq!((emu.state.v[4], emu.state.v[0xf]), (0xff, 0));
+        emu.step().unwrap();
+        assert_eq!(emu.state.ip, 0x20c);
+        emu.step().unwrap();
+        assert_eq!(emu.state.ip, 0x20e);
+        emu.step().unwrap();
+        assert_eq!(emu.state.ip, 0x20e);
+    }
+
+    #[test]
+    fn settle_waits_out_a_draw_burst() {
+        use StepEvent::*;
+        let mut presenter = Presenter {
+            present_on_draw: true,
+            settle: 2,
+            ..Presenter::default()
+        };
+        // Three draws with one instruction between, then compute.
+        let events = [
+            Drew, Executed, Drew, Executed, Drew, Executed, Executed, Executed,
+        ];
+        let presented: Vec<bool> = events
+            .iter()
+            .map(|&e| presenter.should_present(e))
+            .collect();
+        assert_eq!(
+            presented,
+            [false, false, false, false, false, false, true, false]
+        );
+        // Later draws in the frame wait for its end.
+        assert!(!presenter.should_present(Drew));
+        assert!(!presenter.should_present(Executed));
+        assert!(!presenter.should_present(Executed));
+        assert!(presenter.should_present(FrameComplete));
+    }
+
+    #[test]
+    fn step_cpu_leaves_the_timers_to_the_host() {
+        // LD V0, 5 ; LD DT, V0 ; JP 204
+        let mut emu = emulator(&[0x60, 0x05, 0xf0, 0x15, 0x12, 0x04]);
+        for _ in 0..100 {
+            emu.step_cpu().unwrap();
+            assert!(emu.delay_timer() == 0 || emu.delay_timer() == 5);
+        }
+        assert_eq!(emu.delay_timer(), 5);
+        assert_eq!((emu.cycles_executed(), emu.emulated_frame()), (100, 0));
+        emu.tick_timers_once();
+        assert_eq!(emu.delay_timer(), 4);
+        assert_eq!(emu.emulated_frame(), 1);
+    }
+
+    #[test]
+    fn endless_roms_time_out_at_max_cycles() {
+        // ADD V0, 1 ; JP 200, which never jumps to itself
+        let rom = [0x70, 0x01, 0x12, 0x00];
+        let path = temp_path("endless.ch8");
+        std::fs::write(&path, rom).unwrap();
+        let run = |flags: &[&str]| {
+            let mut all = vec!["--headless", "--frames", "1000000"];
+            all.extend_from_slice(flags);
+            all.push(&path);
+            headless::run(&options::parse(&args(&all)).unwrap()).unwrap()
+        };
+        assert_eq!(run(&["--max-cycles", "5000"]), headless::Stop::TimedOut);
+        assert_eq!(run(&["--max-seconds", "0.1"]), headless::Stop::TimedOut);
+
+        let mut emu = emulator(&rom);
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        let summary = headless::timeout_summary(&mut emu, Some(0x200));
+        assert_eq!(summary, "timed out: ip=202 last=200 ADD V0, 0x01 cycles=5");
+    }
+
+    #[test]
+    fn key_presses_drive_the_counter() {
+        let mut emu = emulator(&KEY5_COUNTER);
+        headless::run_frame(&mut emu).unwrap();
+        for _ in 0..3 {
+            emu.set_key(5, true);
+            headless::run_frame(&mut emu).unwrap();
+            emu.set_key(5, false);
+            headless::run_frame(&mut emu).unwrap();
+        }
+        assert_eq!(emu.state.v[0], 3);
+        assert_eq!(emu.screen_hash(), glyph_screen_hash(3));
+    }
+
+    #[test]
+    fn held_key_counts_once() {
+        let mut emu = emulator(&KEY5_COUNTER);
+        emu.set_key(5, true);
+        for _ in 0..5 {
+            headless::run_frame(&mut emu).unwrap();
+        }
+        assert_eq!(emu.state.v[0], 0);
+        emu.set_key(5, false);
+        headless::run_frame(&mut emu).unwrap();
+        assert_eq!(emu.state.v[0], 1);
+        assert_eq!(emu.screen_hash(), glyph_screen_hash(1));
+    }
 }
@@ -0,0 +1,211 @@
+use crate::headless::Dump;
+use crate::{Behavior, Config, Denied, OffAlpha, RomWrites, SoundFlash, TimingMode};
+use anyhow::{anyhow, bail, Result};
+
+const USAGE: &str = "\
+usage: chip8 [options] [rom]
+       chip8 disasm <rom>
+       chip8 diff-roms <a> <b> [options]   compare frame by frame, without key input
+
+options:
+  --builtin NAME                 run a ROM built into the binary; `list` names them
+  --timing MODE                  frame[:N] (default): N instructions per 60Hz frame
+                                 legacy: one instruction per loop, wall-clock timers
+                                 virtual[:N]: tick every N instructions, unpaced
+  --seed N                       seed the random number generator
+  --headless                     run without a window, for --frames frames or until halted
+  --frames N                     frames to run headless (default 600)
+  --max-cycles N                 stop a headless run after N instructions, as timed out
+  --max-seconds S                stop a headless run after S seconds, as timed out
+  --suggest-speed                profile the ROM headless and suggest --timing flags
+  --dump-final WHAT              after a headless run print regs, mem:0xNNN:LEN, screen,
+                                 screen-hash or fingerprint
+  --fingerprint                  run headless and print one hash of the final state
+  --stack-depth N                allow N nested calls (default 16)
+  --getkey-on-press              FX0A returns when a key goes down, not when released
+  --shift-in-place               8XY6/8XYE shift VX itself instead of VY
+  --display-wait                 DXYN waits for the next 60Hz frame
+  --wrap-memory                  writes past 0xFFF wrap to 0 instead of failing
+  --debounce-keys                EX9E sees a held key once until it is released
+  --warn-draw-low                warn when DXYN reads sprites from uninitialized low memory
+  --rom-writes allow|warn|trap   what to do when the program writes over its own image;
+                                 snapshots keep where the image was
+  --deny PATTERN                 refuse opcodes like FX75 or 8XYN; may be repeated
+  --denied error|skip            stop on a denied opcode (default) or step over it
+  --present-on-draw              show the first draw of each frame immediately
+  --settle N                     with --present-on-draw, wait until N instructions
+                                 after the last draw in a burst
+  --inspect                      show the pixel under the mouse in the title bar
+  --keymap PATH                  read hex pad keys and save-state/load-state/reset keys
+                                 from lines like `a = Z` or `save-state = LeftCtrl+S`
+  --load-state PATH              start from a snapshot instead of loading a ROM
+  --autosave PATH                write a snapshot to PATH when the window run ends
+  --trace                        print every instruction as it executes
+  --debug                        start paused in the debugger
+  --verbose                      print the resolved configuration at startup
+  --capabilities                 print which instruction sets this build runs and exit
+  --analyze                      report how much of the rom decodes, and whether its
+                                 bytes look swapped
+  --transparent-off              give off pixels zero alpha, for compositing
+  --show-sound                   mark the bottom-right corner while the sound timer runs
+  --sound-flash border|screen[:P]
+                                 invert the border or whole screen, P percent (default
+                                 100), while the sound timer runs
+
+keys (see --keymap):
+  F5                             save a snapshot next to the ROM
+  F9                             load that snapshot
+  F2                             reset; with --seed the run repeats exactly
+  Escape                         quit";
+
+pub struct Options {
+    pub rom: Option<String>,
+    pub builtin: Option<String>,
+    pub config: Config,
+    pub headless: bool,
+    pub suggest_speed: bool,
+    pub frames: u64,
+    pub max_cycles: Option<u64>,
+    pub max_seconds: Option<f64>,
+    pub dumps: Vec<Dump>,
+    pub present_on_draw: bool,
+    pub settle: u32,
+    pub inspect: bool,
+    pub load_state: Option<String>,
+    pub autosave: Option<String>,
+    pub keymap: Option<String>,
+    pub trace: bool,
+    pub debug: bool,
+    pub verbose: bool,
+    pub capabilities: bool,
+    pub analyze: bool,
+    pub off_alpha: OffAlpha,
+    pub show_sound: bool,
+    pub sound_flash: Option<SoundFlash>,
+}
+
+fn parse_timing(value: &str) -> Result<TimingMode> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("legacy"), None) | (Some("realtime"), None) => Ok(TimingMode::Legacy),
+        (Some(mode @ "virtual"), cycles) | (Some(mode @ "frame"), cycles) => {
+            let cycles_per_tick = match cycles {
+                Some(n) => n.parse()?,
+                None => 10,
+            };
+            if cycles_per_tick == 0 {
+                bail!("{} timing needs at least one cycle per tick", mode);
+            }
+            if mode == "frame" {
+                Ok(TimingMode::Frame {
+                    cycles_per_frame: cycles_per_tick,
+                })
+            } else {
+                Ok(TimingMode::Virtual { cycles_per_tick })
+            }
+        }
+        _ => bail!("unknown timing mode '{}'", value),
+    }
+}
+
+pub fn parse(args: &[String]) -> Result<Options> {
+    let mut options = Options {
+        rom: None,
+        builtin: None,
+        config: Config::default(),
+        headless: false,
+        suggest_speed: false,
+        frames: 600,
+        max_cycles: None,
+        max_seconds: None,
+        dumps: Vec::new(),
+        present_on_draw: false,
+        settle: 0,
+        inspect: false,
+        load_state: None,
+        autosave: None,
+        keymap: None,
+        trace: false,
+        debug: false,
+        verbose: false,
+        capabilities: false,
+        analyze: false,
+        off_alpha: OffAlpha::Opaque,
+        show_sound: false,
+        sound_flash: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("{} needs a value\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--timing" => options.config.timing = parse_timing(value()?)?,
+            "--seed" => options.config.seed = Some(value()?.parse()?),
+            "--builtin" => options.builtin = Some(value()?.clone()),
+            "--headless" => options.headless = true,
+            "--suggest-speed" => options.suggest_speed = true,
+            "--fingerprint" => {
+                options.headless = true;
+                options.dumps.push(Dump::Fingerprint);
+            }
+            "--frames" => options.frames = value()?.parse()?,
+            "--max-cycles" => options.max_cycles = Some(value()?.parse()?),
+            "--max-seconds" => {
+                let seconds: f64 = value()?.parse()?;
+                if !seconds.is_finite() || seconds <= 0.0 {
+                    bail!("--max-seconds needs a positive number of seconds");
+                }
+                options.max_seconds = Some(seconds);
+            }
+            "--dump-final" => options.dumps.push(value()?.parse()?),
+            "--stack-depth" => {
+                options.config.stack_depth = value()?.parse()?;
+                // Snapshots store the depth in 16 bits.
+                if options.config.stack_depth == 0 || options.config.stack_depth > 0xffff {
+                    bail!("stack depth must be from 1 to 65535");
+                }
+            }
+            "--getkey-on-press" => options.config.quirks.getkey_on_release = false,
+            "--shift-in-place" => options.config.quirks.shift = Behavior::New,
+            "--display-wait" => options.config.quirks.display_wait = true,
+            "--wrap-memory" => options.config.quirks.memory_wrap = true,
+            "--debounce-keys" => options.config.quirks.key_debounce = true,
+            "--warn-draw-low" => options.config.warn_draw_low = true,
+            "--deny" => options.config.policy.denied.push(value()?.parse()?),
+            "--denied" => {
+                options.config.policy.action = match value()?.as_str() {
+                    "error" => Denied::Error,
+                    "skip" => Denied::Skip,
+                    other => bail!("--denied takes error or skip, got '{}'", other),
+                }
+            }
+            "--rom-writes" => {
+                options.config.rom_writes = match value()?.as_str() {
+                    "allow" => RomWrites::Allow,
+                    "warn" => RomWrites::Warn,
+                    "trap" => RomWrites::Trap,
+                    other => bail!("--rom-writes takes allow, warn or trap, got '{}'", other),
+                }
+            }
+            "--present-on-draw" => options.present_on_draw = true,
+            "--settle" => options.settle = value()?.parse()?,
+            "--inspect" => options.inspect = true,
+            "--keymap" => options.keymap = Some(value()?.clone()),
+            "--load-state" => options.load_state = Some(value()?.clone()),
+            "--autosave" => options.autosave = Some(value()?.clone()),
+            "--trace" => options.trace = true,
+            "--debug" => options.debug = true,
+            "--verbose" => options.verbose = true,
+            "--capabilities" => options.capabilities = true,
+            "--analyze" => options.analyze = true,
+            "--transparent-off" => options.off_alpha = OffAlpha::Transparent,
+            "--show-sound" => options.show_sound = true,
+            "--sound-flash" => options.sound_flash = Some(value()?.parse()?),
+            _ if arg.starts_with("--") => bail!("unknown option '{}'\n{}", arg, USAGE),
+            _ => options.rom = Some(arg.clone()),
+        }
+    }
+    Ok(options)
+}
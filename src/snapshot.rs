@@ -0,0 +1,162 @@
+use crate::{Address, Emulator, HEIGHT, WIDTH};
+use anyhow::{anyhow, bail, Result};
+
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u8 = 3;
+
+/// Serializes everything needed to resume execution, except the random
+/// number generator, which keeps its own state.
+pub fn save(emu: &Emulator) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MAGIC.len() + emu.memory.mem.len() + WIDTH * HEIGHT + 128);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&emu.rom_hash.to_le_bytes());
+
+    let s = &emu.state;
+    out.extend_from_slice(&s.ip.to_le_bytes());
+    out.extend_from_slice(&s.address.0.to_le_bytes());
+    out.extend_from_slice(&s.v);
+    out.push(s.waiting_key.unwrap_or(0xff));
+    out.push(s.add_overflowed as u8);
+
+    let stack = &emu.memory.stack.entries;
+    out.extend_from_slice(&(stack.len() as u16).to_le_bytes());
+    for addr in stack {
+        out.extend_from_slice(&addr.to_le_bytes());
+    }
+
+    out.push(emu.timer.delay_value);
+    out.push(emu.timer.sound_value);
+    out.extend_from_slice(&emu.timer.cycles.to_le_bytes());
+    out.extend_from_slice(&emu.cycles.to_le_bytes());
+    out.extend_from_slice(&emu.frames.to_le_bytes());
+    let rom_range = &emu.memory.rom_range;
+    out.extend_from_slice(&(rom_range.start as u16).to_le_bytes());
+    out.extend_from_slice(&(rom_range.end as u16).to_le_bytes());
+
+    out.extend_from_slice(&emu.memory.mem);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            out.push(emu.pixel(x, y) as u8);
+        }
+    }
+    out
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            bail!("snapshot is truncated");
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+}
+
+/// Returns the hash of the ROM the snapshot was taken from, without
+/// restoring anything.
+pub fn rom_hash(data: &[u8]) -> Result<u64> {
+    let mut r = Reader { data };
+    if r.take(MAGIC.len())? != MAGIC {
+        bail!("not a CHIP-8 snapshot");
+    }
+    let version = r.u8()?;
+    if version != VERSION {
+        bail!("unsupported snapshot version {}", version);
+    }
+    r.u64()
+}
+
+pub fn restore(emu: &mut Emulator, data: &[u8]) -> Result<()> {
+    let rom_hash = rom_hash(data)?;
+    let mut r = Reader {
+        data: &data[MAGIC.len() + 1 + 8..],
+    };
+
+    let ip = r.u16()?;
+    let address = r.u16()?;
+    let mut v = [0; 16];
+    v.copy_from_slice(r.take(16)?);
+    let waiting_key = match r.u8()? {
+        0xff => None,
+        k => Some(k),
+    };
+    let add_overflowed = r.u8()? != 0;
+
+    let depth = r.u16()? as usize;
+    if depth > emu.memory.stack.depth {
+        bail!(
+            "snapshot has {} nested calls but the stack only allows {}",
+            depth,
+            emu.memory.stack.depth
+        );
+    }
+    let stack = (0..depth).map(|_| r.u16()).collect::<Result<Vec<u16>>>()?;
+
+    let delay = r.u8()?;
+    let sound = r.u8()?;
+    let timer_cycles = r.u32()?;
+    let cycles = r.u64()?;
+    let frames = r.u64()?;
+    let rom_range = r.u16()? as usize..r.u16()? as usize;
+    if rom_range.start > rom_range.end || rom_range.end > emu.memory.mem.len() {
+        bail!("snapshot has an invalid ROM range");
+    }
+    let mem = r.take(emu.memory.mem.len())?;
+    let pixels = r.take(WIDTH * HEIGHT)?;
+    if !r.data.is_empty() {
+        return Err(anyhow!("snapshot has {} trailing bytes", r.data.len()));
+    }
+
+    // Only touch the emulator once the whole snapshot has parsed.
+    emu.rom_hash = rom_hash;
+    emu.state.ip = ip;
+    emu.state.address = Address(address);
+    emu.state.v = v;
+    emu.state.waiting_key = waiting_key;
+    emu.state.add_overflowed = add_overflowed;
+    emu.memory.stack.entries = stack;
+    emu.timer.delay_value = delay;
+    emu.timer.sound_value = sound;
+    // A snapshot from a slower timing mode can be further into its frame
+    // than this mode's frames are long; the tick then falls on the next step.
+    emu.timer.cycles = match emu.timer.mode.cycles_per_tick() {
+        Some(cycles_per_tick) => timer_cycles.min(cycles_per_tick.saturating_sub(1)),
+        None => timer_cycles,
+    };
+    emu.cycles = cycles;
+    emu.frames = frames;
+    emu.memory.rom_range = rom_range;
+    emu.memory.mem.copy_from_slice(mem);
+    emu.memory.decode_cache.clear();
+    for (i, &on) in pixels.iter().enumerate() {
+        emu.set_pixel(i % WIDTH, i / WIDTH, on != 0);
+    }
+    Ok(())
+}